@@ -0,0 +1,75 @@
+use std::sync::{Arc, Barrier};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log_loki::{LogContainer, LogMetricConfBuilder, Level};
+
+const PUSHES_PER_THREAD:usize = 100_000;
+const DRAIN_HOLD:Duration = Duration::from_micros(200);
+
+fn container()->Arc<LogContainer>{
+    Arc::new(LogContainer::with_config(LogMetricConfBuilder::new()
+        .add_labels(&["bench"])
+        .set_default_capacity(1 << 16)
+        .build()))
+}
+
+fn run<P>(name:&str, threads:usize, container:Arc<LogContainer>, push:P)
+    where P:Fn(usize)+Send+Sync+'static
+{
+    let metric = container.get(&["drain"]);
+    let done = Arc::new(AtomicBool::new(false));
+    let drainer = {
+        let done = done.clone();
+        thread::spawn(move||while !done.load(Ordering::Relaxed) {
+            // Hold the stream lock for a while, the same way a scrape holds it while sending.
+            let mut m = metric.lock().unwrap();
+            m.collect_pending();
+            while m.pop().is_some() {}
+            thread::sleep(DRAIN_HOLD);
+        })
+    };
+
+    let push = Arc::new(push);
+    let barrier = Arc::new(Barrier::new(threads + 1));
+    let workers:Vec<_> = (0..threads).map(|t|{
+        let push = push.clone();
+        let barrier = barrier.clone();
+        thread::spawn(move||{
+            barrier.wait();
+            for i in 0..PUSHES_PER_THREAD {
+                push(t * PUSHES_PER_THREAD + i);
+            }
+        })
+    }).collect();
+    barrier.wait();
+    let start = Instant::now();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    let elapsed = start.elapsed();
+    done.store(true, Ordering::Relaxed);
+    drainer.join().unwrap();
+
+    let total = threads * PUSHES_PER_THREAD;
+    println!("{:<8} threads={:<2} {:>8.1} ns/push {:>10.0} pushes/s",
+        name, threads, elapsed.as_nanos() as f64 / total as f64, total as f64 / elapsed.as_secs_f64());
+}
+
+fn main(){
+    for threads in [1, 2, 4, 8, 16].iter().cloned() {
+        let shared = container();
+        let locked = shared.clone();
+        run("mutex", threads, shared, move|i|{
+            let metric = locked.get(&["drain"]);
+            metric.lock().unwrap().push_lazy_with_level(Level::Info, ||format!("message {}", i));
+        });
+
+        let shared = container();
+        let sender = shared.sender(&["drain"]);
+        run("sender", threads, shared, move|i|{
+            sender.push_lazy(Level::Info, ||format!("message {}", i));
+        });
+    }
+}
@@ -0,0 +1,54 @@
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use quick_protobuf::message::MessageWrite;
+use log_loki::bench::{LokiStream, LokiEntry, ProtoEncoder, logproto};
+
+const STREAMS:usize = 50;
+const ENTRIES:usize = 200;
+const ROUNDS:usize = 200;
+
+fn streams()->Vec<LokiStream>{
+    (0..STREAMS).map(|s|LokiStream{
+        labels: Vec::new(),
+        selector: format!("{{app=\"bench\",stream=\"{}\"}}", s).into(),
+        entries: (0..ENTRIES).map(|e|LokiEntry{
+            ts: UNIX_EPOCH + Duration::from_nanos(1_700_000_000_000_000_000 + e as u64),
+            line: format!("GET /api/v1/items/{} status=200 took={}ms user=someone@example.com", e, e % 97),
+            metadata: if e % 4 == 0 { vec![("trace_id".to_string(), format!("{:032x}", e))] } else { Vec::new() },
+        }).collect(),
+    }).collect()
+}
+
+fn report(name:&str, bytes:usize, elapsed:Duration){
+    println!("{:<14} {:>8.1} MiB/s {:>10.1} us/request",
+        name, (bytes * ROUNDS) as f64 / elapsed.as_secs_f64() / (1 << 20) as f64, elapsed.as_micros() as f64 / ROUNDS as f64);
+}
+
+fn main(){
+    let streams = streams();
+
+    let mut buf = Vec::new();
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        buf.clear();
+        // Previous path: owned logproto structs, then quick-protobuf's writer.
+        let request = logproto::PushRequest::from(streams.iter().map(|s|logproto::Stream{
+            labels: s.selector().to_string().into(),
+            entries: s.entries.iter().map(|e|logproto::Entry{
+                ts: Some(e.ts.into()),
+                line: e.line.clone().into(),
+                structuredMetadata: e.metadata.iter().map(|(k, v)|logproto::LabelPair{ name: k.clone().into(), value: v.clone().into() }).collect(),
+            }).collect(),
+        }).collect::<Vec<_>>());
+        request.write_message(&mut quick_protobuf::Writer::new(&mut buf)).unwrap();
+    }
+    report("quick-protobuf", buf.len(), start.elapsed());
+
+    let mut encoder = ProtoEncoder::new();
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        buf.clear();
+        encoder.encode(&streams, &mut buf);
+    }
+    report("ProtoEncoder", buf.len(), start.elapsed());
+}
@@ -0,0 +1,82 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use log_loki::{LogContainer, LogMetric, LogMetricConfBuilder};
+
+struct Counting;
+
+static ALLOCATIONS:AtomicUsize = AtomicUsize::new(0);
+static BYTES:AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout:Layout)->*mut u8{
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr:*mut u8, layout:Layout){
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL:Counting = Counting;
+
+const STREAMS:usize = 1000;
+const SCRAPES:usize = 100;
+
+fn measure<F:FnMut()>(name:&str, mut run:F){
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let bytes = BYTES.load(Ordering::Relaxed);
+    run();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    let bytes = BYTES.load(Ordering::Relaxed) - bytes;
+    println!("{:<24} {:>10} allocations {:>12} bytes", name, allocations, bytes);
+}
+
+// The selector rendering used before streams cached it.
+fn render_per_scrape(metric:&LogMetric, names:&[&str])->String{
+    let labels:Vec<(String, String)> = vec![("app".to_string(), "bench".to_string())].into_iter()
+        .chain(names.iter().map(|n|n.to_string()).zip(metric.labels().iter().cloned()))
+        .collect();
+    let mut selector = "{".to_string();
+    let parts:Vec<String> = labels.iter().map(|(k,v)|format!("{}=\"{}\"",k,v)).collect();
+    selector.push_str(parts.join(",").as_str());
+    selector.push('}');
+    selector
+}
+
+fn main(){
+    let names = ["host", "method", "status"];
+    let container = LogContainer::with_config(LogMetricConfBuilder::new()
+        .add_const_label("app", "bench")
+        .add_labels(&names)
+        .build());
+
+    let mut metrics = Vec::with_capacity(STREAMS);
+    measure("create streams", ||{
+        for i in 0..STREAMS {
+            let host = format!("web-{}", i / 10);
+            let status = (200 + i % 5).to_string();
+            metrics.push(container.get(&[&host, ["GET", "POST"][i % 2], &status]));
+        }
+    });
+    let metrics:Vec<_> = metrics.iter().map(|m|m.lock().unwrap()).collect();
+
+    measure("render per scrape", ||{
+        for _ in 0..SCRAPES {
+            for metric in metrics.iter() {
+                std::hint::black_box(render_per_scrape(metric, &names));
+            }
+        }
+    });
+    measure("cached selector", ||{
+        for _ in 0..SCRAPES {
+            for metric in metrics.iter() {
+                std::hint::black_box(Arc::clone(metric.selector()));
+            }
+        }
+    });
+}
@@ -1,7 +1,7 @@
 extern crate pb_rs;
 
-use std::path::{Path, PathBuf};
-use pb_rs::types::{Config, FileDescriptor};
+use std::path::PathBuf;
+use pb_rs::types::FileDescriptor;
 use std::env;
 use pb_rs::ConfigBuilder;
 
@@ -17,6 +17,6 @@ fn main() {
         .build();
 
     for ref config in configs{
-        FileDescriptor::write_proto(&config).unwrap();
+        FileDescriptor::write_proto(config).unwrap();
     }
 }
\ No newline at end of file
@@ -0,0 +1,73 @@
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, LitStr};
+
+#[proc_macro_derive(LokiLabels, attributes(loki))]
+pub fn derive_loki_labels(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(Error::new(Span::call_site(), "LokiLabels can only be derived for structs with named fields")),
+        },
+        _ => return Err(Error::new(Span::call_site(), "LokiLabels can only be derived for structs")),
+    };
+
+    let mut idents = Vec::with_capacity(fields.len());
+    let mut names: Vec<LitStr> = Vec::with_capacity(fields.len());
+    for field in fields.iter() {
+        let ident = field.ident.as_ref().unwrap();
+        let mut name = LitStr::new(&ident.to_string(), ident.span());
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("loki")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename") {
+                    name = meta.value()?.parse()?;
+                    Ok(())
+                } else {
+                    Err(meta.error("unsupported loki attribute, expected `rename`"))
+                }
+            })?;
+        }
+        if !is_valid_label_name(&name.value()) {
+            return Err(Error::new(name.span(), format!("`{}` is not a valid Loki label name", name.value())));
+        }
+        if names.iter().any(|n| n.value() == name.value()) {
+            return Err(Error::new(name.span(), format!("duplicate Loki label name `{}`", name.value())));
+        }
+        idents.push(ident);
+        names.push(name);
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::log_loki::LokiLabels for #name #ty_generics #where_clause {
+            fn label_names() -> &'static [&'static str] {
+                &[#(#names),*]
+            }
+
+            fn label_values(&self) -> ::std::vec::Vec<::std::string::String> {
+                ::std::vec![#(::std::string::ToString::to_string(&self.#idents)),*]
+            }
+        }
+    })
+}
+
+fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    !name.starts_with("__") && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
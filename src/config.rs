@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::errors::*;
+use crate::loki::{LokiScrapeConfig, PushFormat, Compression};
+use crate::models::{LogMetricConfBuilder, Level, LinePolicy};
+use crate::scrape::ScrapeConfig;
+
+const LOKI_KEYS:[&str; 15] = ["url", "tenant", "scrape_interval", "connect_timeout", "write_timeout", "read_timeout",
+    "flush_entries", "flush_bytes", "batch_entries", "format", "compression", "compression_level", "min_compress_size",
+    "max_retries", "retry_backoff"];
+const LOG_KEYS:[&str; 9] = ["default_capacity", "min_level", "flush_level", "capture_location", "max_line_size",
+    "line_policy", "rate_limit", "rate_burst", "sample_every"];
+// `LOKI_` variables read by other tools, mostly logcli, that may share the
+// environment; everything else under the prefix must be one of ours.
+const FOREIGN_KEYS:[&str; 12] = ["ADDR", "USERNAME", "PASSWORD", "ORG_ID", "BEARER_TOKEN", "BEARER_TOKEN_FILE",
+    "CA_CERT_PATH", "CLIENT_CERT_PATH", "CLIENT_KEY_PATH", "TLS_SKIP_VERIFY", "QUERY_TAGS", "AUTH_HEADER"];
+const DEFAULT_SCRAPE_INTERVAL_MS:u64 = 1000;
+
+/// A single setting as read from the environment or a file.
+#[derive(Clone, Copy)]
+#[cfg_attr(not(feature = "toml"), allow(dead_code))]
+enum Raw<'a> {
+    Text(&'a str),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl<'a> Raw<'a> {
+    fn text(self, key:&str)->Result<&'a str>{
+        match self {
+            Raw::Text(s) if !s.trim().is_empty() => Ok(s.trim()),
+            _ => Err(invalid(key, "expected a non-empty string")),
+        }
+    }
+
+    fn uint(self, key:&str)->Result<u64>{
+        match self {
+            Raw::Text(s) => s.trim().parse::<u64>().map_err(|_|invalid(key, "expected a non-negative integer")),
+            Raw::Int(i) if i >= 0 => Ok(i as u64),
+            _ => Err(invalid(key, "expected a non-negative integer")),
+        }
+    }
+
+    fn float(self, key:&str)->Result<f64>{
+        match self {
+            Raw::Text(s) => s.trim().parse::<f64>().map_err(|_|invalid(key, "expected a number")),
+            Raw::Int(i) => Ok(i as f64),
+            Raw::Float(f) => Ok(f),
+            Raw::Bool(_) => Err(invalid(key, "expected a number")),
+        }
+    }
+
+    fn boolean(self, key:&str)->Result<bool>{
+        match self {
+            Raw::Text("true") | Raw::Text("1") => Ok(true),
+            Raw::Text("false") | Raw::Text("0") => Ok(false),
+            Raw::Bool(b) => Ok(b),
+            _ => Err(invalid(key, "expected true or false")),
+        }
+    }
+}
+
+// Arrays, tables and dates are never valid values.
+#[cfg(feature = "toml")]
+fn toml_raw(value:&toml::Value)->Raw<'_>{
+    match value {
+        toml::Value::String(s) => Raw::Text(s),
+        toml::Value::Integer(i) => Raw::Int(*i),
+        toml::Value::Float(f) => Raw::Float(*f),
+        toml::Value::Boolean(b) => Raw::Bool(*b),
+        _ => Raw::Text(""),
+    }
+}
+
+fn invalid(key:&str, message:&str)->Error{
+    Error::InvalidConfig(format!("{}: {}", key, message))
+}
+
+fn level(raw:Raw, key:&str)->Result<Level>{
+    let name = raw.text(key)?;
+    Level::ALL.iter().cloned()
+        .find(|l|l.as_str().eq_ignore_ascii_case(name))
+        .ok_or_else(||invalid(key, "expected one of trace, debug, info, warn, error"))
+}
+
+/// Push settings and stream defaults loaded from the environment or a TOML
+/// file. Every value is validated; anything malformed is an
+/// `Error::InvalidConfig` naming the offending key.
+#[derive(Clone)]
+pub struct Config {
+    scrape: LokiScrapeConfig,
+    log: LogMetricConfBuilder,
+}
+
+impl Config {
+    /// Reads `LOKI_<KEY>` for push settings (`LOKI_URL` is required,
+    /// `LOKI_TENANT` sets `X-Scope-OrgID`) and `LOKI_LOG_<KEY>` for stream
+    /// defaults. Any other `LOKI_` variable is an error, except the ones
+    /// logcli reads, such as `LOKI_ADDR`, which are left alone.
+    pub fn from_env()->Result<Self>{
+        Config::from_vars(std::env::vars())
+    }
+
+    pub fn from_vars<I:IntoIterator<Item=(String, String)>>(vars:I)->Result<Self>{
+        let vars:HashMap<String, String> = vars.into_iter().collect();
+        let mut names:Vec<&String> = vars.keys().filter(|k|k.starts_with("LOKI_")).collect();
+        names.sort();
+        for name in names {
+            let upper = |keys:&[&str], key:&str|keys.iter().any(|k|k.to_ascii_uppercase() == key);
+            let known = match name.strip_prefix("LOKI_LOG_") {
+                Some(key) => upper(&LOG_KEYS, key),
+                None => {
+                    let key = &name["LOKI_".len()..];
+                    upper(&LOKI_KEYS, key) || FOREIGN_KEYS.contains(&key)
+                }
+            };
+            if !known {
+                return Err(invalid(name, "unknown variable"));
+            }
+        }
+        let lookup = |prefix:&str, keys:&[&'static str]|->Vec<(&'static str, String)>{
+            keys.iter()
+                .filter_map(|key|vars.get(&format!("{}{}", prefix, key.to_ascii_uppercase())).map(|v|(*key, v.clone())))
+                .collect()
+        };
+        let loki = lookup("LOKI_", &LOKI_KEYS);
+        let log = lookup("LOKI_LOG_", &LOG_KEYS);
+        Config::build(
+            loki.iter().map(|(k, v)|(*k, Raw::Text(v))),
+            log.iter().map(|(k, v)|(*k, Raw::Text(v))))
+    }
+
+    /// Parses a file with `[loki]` and `[log]` tables using the same keys as
+    /// the environment, in lower case. Unknown tables or keys are rejected.
+    #[cfg(feature = "toml")]
+    pub fn from_toml_str(source:&str)->Result<Self>{
+        let table:toml::Table = source.parse().map_err(|e:toml::de::Error|Error::InvalidConfig(e.message().to_string()))?;
+        let mut loki = Vec::new();
+        let mut log = Vec::new();
+        for (section, value) in table.iter() {
+            let (keys, target):(&[&str], &mut Vec<(&str, &toml::Value)>) = match section.as_str() {
+                "loki" => (&LOKI_KEYS, &mut loki),
+                "log" => (&LOG_KEYS, &mut log),
+                _ => return Err(invalid(section, "unknown section")),
+            };
+            let entries = value.as_table().ok_or_else(||invalid(section, "expected a table"))?;
+            for (key, value) in entries.iter() {
+                if !keys.contains(&key.as_str()) {
+                    return Err(invalid(&format!("{}.{}", section, key), "unknown key"));
+                }
+                target.push((key.as_str(), value));
+            }
+        }
+        Config::build(loki.into_iter().map(|(k, v)|(k, toml_raw(v))), log.into_iter().map(|(k, v)|(k, toml_raw(v))))
+    }
+
+    #[cfg(feature = "toml")]
+    pub fn from_file<P:AsRef<std::path::Path>>(path:P)->Result<Self>{
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)
+            .map_err(|e|Error::InvalidConfig(format!("{}: {}", path.display(), e)))?;
+        Config::from_toml_str(&source)
+    }
+
+    pub fn scrape(&self)->&LokiScrapeConfig{
+        &self.scrape
+    }
+
+    /// Stream defaults; add labels to get a `LogMetricConf`.
+    pub fn log(&self)->&LogMetricConfBuilder{
+        &self.log
+    }
+
+    pub fn into_parts(self)->(LokiScrapeConfig, LogMetricConfBuilder){
+        (self.scrape, self.log)
+    }
+
+    fn build<'a, L, M>(loki:L, log:M)->Result<Self>
+        where L:Iterator<Item=(&'a str, Raw<'a>)>, M:Iterator<Item=(&'a str, Raw<'a>)>
+    {
+        let loki:HashMap<&str, Raw> = loki.collect();
+        let url = loki.get("url").ok_or_else(||invalid("url", "missing"))?.text("url")?;
+        if !(url.starts_with("http://") || url.starts_with("https://")) {
+            return Err(invalid("url", "expected an http:// or https:// URL"));
+        }
+        if url.contains('?') {
+            return Err(invalid("url", "set options as separate keys, not as a query string"));
+        }
+        let mut scrape = LokiScrapeConfig::new(url, DEFAULT_SCRAPE_INTERVAL_MS);
+        for (key, raw) in loki.iter().map(|(k, v)|(*k, *v)) {
+            scrape = match key {
+                "url" => scrape,
+                "tenant" => scrape.set_tenant(raw.text(key)?),
+                "scrape_interval" => match raw.uint(key)? {
+                    0 => return Err(invalid(key, "must be greater than zero")),
+                    ms => scrape.set_scrape_interval(Duration::from_millis(ms)),
+                },
+                "connect_timeout" => scrape.set_connect_timeout(raw.uint(key)?),
+                "write_timeout" => scrape.set_write_timeout(raw.uint(key)?),
+                "read_timeout" => scrape.set_read_timeout(raw.uint(key)?),
+                "flush_entries" => scrape.set_flush_entries(raw.uint(key)? as usize),
+                "flush_bytes" => scrape.set_flush_bytes(raw.uint(key)? as usize),
+                "batch_entries" => scrape.set_batch_entries(raw.uint(key)? as usize),
+                "format" => {
+                    let format = match raw.text(key)? {
+                        "protobuf" => PushFormat::Protobuf,
+                        "json" => PushFormat::Json,
+                        _ => return Err(invalid(key, "expected protobuf or json")),
+                    };
+                    scrape.set_format(format)
+                },
+                "compression" => {
+                    let value = match raw.text(key)? {
+                        "none" => Compression::None,
+                        "snappy" => Compression::Snappy,
+                        "gzip" => Compression::Gzip,
+                        "deflate" => Compression::Deflate,
+                        _ => return Err(invalid(key, "expected none, snappy, gzip or deflate")),
+                    };
+                    scrape.set_compression(value)
+                },
+                "compression_level" => match raw.uint(key)? {
+                    level @ 0..=9 => scrape.set_compression_level(level as u32),
+                    _ => return Err(invalid(key, "expected a level from 0 to 9")),
+                },
+                "min_compress_size" => scrape.set_min_compress_size(raw.uint(key)? as usize),
+                "max_retries" => scrape.set_max_retries(raw.uint(key)?.min(u32::MAX as u64) as u32),
+                "retry_backoff" => scrape.set_retry_backoff(Duration::from_millis(raw.uint(key)?)),
+                _ => return Err(invalid(key, "unknown key")),
+            };
+        }
+        scrape.validate()?;
+
+        let log:HashMap<&str, Raw> = log.collect();
+        let mut builder = LogMetricConfBuilder::new();
+        let mut max_line_size = None;
+        let mut line_policy = LinePolicy::Truncate;
+        let mut rate_limit = None;
+        let mut rate_burst = None;
+        for (key, raw) in log.iter().map(|(k, v)|(*k, *v)) {
+            builder = match key {
+                "default_capacity" => builder.set_default_capacity(raw.uint(key)? as usize),
+                "min_level" => builder.set_min_level(level(raw, key)?),
+                "flush_level" => builder.set_flush_level(level(raw, key)?),
+                "capture_location" => builder.set_capture_location(raw.boolean(key)?),
+                "max_line_size" => match raw.uint(key)? {
+                    0 => return Err(invalid(key, "must be greater than zero")),
+                    size => { max_line_size = Some(size as usize); builder },
+                },
+                "line_policy" => {
+                    line_policy = match raw.text(key)? {
+                        "truncate" => LinePolicy::Truncate,
+                        "split" => LinePolicy::Split,
+                        "drop" => LinePolicy::Drop,
+                        _ => return Err(invalid(key, "expected truncate, split or drop")),
+                    };
+                    builder
+                },
+                "rate_limit" => match raw.float(key)? {
+                    r if r > 0.0 && r.is_finite() => { rate_limit = Some(r); builder },
+                    _ => return Err(invalid(key, "must be a positive number of lines per second")),
+                },
+                "rate_burst" => match raw.uint(key)? {
+                    0 => return Err(invalid(key, "must be greater than zero")),
+                    burst => { rate_burst = Some(burst as usize); builder },
+                },
+                "sample_every" => match raw.uint(key)? {
+                    0 => return Err(invalid(key, "must be greater than zero")),
+                    n => builder.set_sample_every(n),
+                },
+                _ => return Err(invalid(key, "unknown key")),
+            };
+        }
+        if let Some(size) = max_line_size {
+            builder = builder.set_max_line_size(size, line_policy);
+        } else if log.contains_key("line_policy") {
+            return Err(invalid("line_policy", "requires max_line_size"));
+        }
+        match (rate_limit, rate_burst) {
+            (Some(per_second), burst) => builder = builder.set_rate_limit(per_second, burst.unwrap_or(per_second.ceil() as usize)),
+            (None, Some(_)) => return Err(invalid("rate_burst", "requires rate_limit")),
+            (None, None) => {}
+        }
+
+        Ok(Config { scrape, log: builder })
+    }
+}
@@ -0,0 +1,85 @@
+use crate::format::FieldValue;
+use crate::models::{LogMessage, LogMetricConf};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub span_id: String,
+}
+
+pub trait ContextProvider: Send + Sync {
+    fn current(&self)->Option<TraceContext>;
+}
+
+impl<F> ContextProvider for F
+    where F: Fn()->Option<TraceContext> + Send + Sync
+{
+    fn current(&self)->Option<TraceContext>{
+        self()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContextMode {
+    Metadata,
+    Fields,
+}
+
+// Called on the pushing thread, so the ids are those of the caller's span
+// and not of whichever thread later drains the message.
+pub(crate) fn attach_current(config:&LogMetricConf, message:&mut LogMessage){
+    if let Some((provider, mode)) = config.get_context_provider() {
+        if let Some(context) = provider.current() {
+            context.attach(message, mode);
+        }
+    }
+}
+
+impl TraceContext {
+    pub(crate) fn attach(self, message:&mut LogMessage, mode:ContextMode){
+        match mode {
+            ContextMode::Metadata => {
+                message.metadata.push(("trace_id".into(), self.trace_id));
+                message.metadata.push(("span_id".into(), self.span_id));
+            },
+            ContextMode::Fields => {
+                message.fields.push(("trace_id", FieldValue::from(self.trace_id)));
+                message.fields.push(("span_id", FieldValue::from(self.span_id)));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "opentelemetry")]
+fn from_span_context(context:&opentelemetry::trace::SpanContext)->Option<TraceContext>{
+    if !context.is_valid() {
+        return None;
+    }
+    Some(TraceContext {
+        trace_id: context.trace_id().to_string(),
+        span_id: context.span_id().to_string(),
+    })
+}
+
+#[cfg(feature = "opentelemetry")]
+pub struct OpenTelemetryContext;
+
+#[cfg(feature = "opentelemetry")]
+impl ContextProvider for OpenTelemetryContext {
+    fn current(&self)->Option<TraceContext>{
+        use opentelemetry::trace::TraceContextExt;
+        from_span_context(opentelemetry::Context::current().span().span_context())
+    }
+}
+
+#[cfg(feature = "tracing")]
+pub struct TracingContext;
+
+#[cfg(feature = "tracing")]
+impl ContextProvider for TracingContext {
+    fn current(&self)->Option<TraceContext>{
+        use opentelemetry::trace::TraceContextExt;
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+        from_span_context(tracing::Span::current().context().span().span_context())
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+use chrono::{DateTime, Utc};
+
+use crate::models::{LogMessage, Dedup, Level, Metadata};
+use crate::loki::FORMAT;
+
+struct Repeated {
+    message: LogMessage,
+    count: usize,
+    last: SystemTime,
+}
+
+impl From<LogMessage> for Repeated {
+    fn from(message:LogMessage)->Self{
+        let last = message.time;
+        Repeated { message, count: 1, last }
+    }
+}
+
+impl Repeated {
+    fn is_same(&self, other:&LogMessage)->bool{
+        self.message.level == other.level && self.message.message == other.message && self.message.metadata == other.metadata
+    }
+
+    fn add(&mut self, other:&LogMessage){
+        self.count += 1;
+        if other.time > self.last {
+            self.last = other.time;
+        }
+    }
+
+    fn into_message(self)->LogMessage{
+        let mut message = self.message;
+        if self.count > 1 {
+            message.message = format!("{} (repeated {} times, first: {}, last: {})",
+                message.message,
+                self.count,
+                DateTime::<Utc>::from(message.time).format(FORMAT),
+                DateTime::<Utc>::from(self.last).format(FORMAT));
+        }
+        message
+    }
+}
+
+pub fn collapse(messages:Vec<LogMessage>, mode:Dedup)->Vec<LogMessage>{
+    match mode {
+        Dedup::Off => messages,
+        Dedup::Consecutive => collapse_consecutive(messages),
+        Dedup::Window => collapse_window(messages),
+    }
+}
+
+fn collapse_consecutive(messages:Vec<LogMessage>)->Vec<LogMessage>{
+    let mut result:Vec<Repeated> = Vec::with_capacity(messages.len());
+    for message in messages {
+        match result.last_mut() {
+            Some(last) if last.is_same(&message) => last.add(&message),
+            _ => result.push(message.into()),
+        }
+    }
+    result.into_iter().map(Repeated::into_message).collect()
+}
+
+fn collapse_window(messages:Vec<LogMessage>)->Vec<LogMessage>{
+    let mut result:Vec<Repeated> = Vec::with_capacity(messages.len());
+    let mut index:HashMap<(Level, String, Metadata), usize> = HashMap::new();
+    for message in messages {
+        let key = (message.level, message.message.clone(), message.metadata.clone());
+        match index.get(&key) {
+            Some(&i) => result[i].add(&message),
+            None => {
+                index.insert(key, result.len());
+                result.push(message.into());
+            }
+        }
+    }
+    result.into_iter().map(Repeated::into_message).collect()
+}
@@ -1,8 +1,91 @@
-error_chain!{
-    errors {
-        SerializeError(e: quick_protobuf::Error) {
-            description("Serialization error")
-            display("Serialization error as '{:?}'", e)
+use std::fmt;
+use std::time::Duration;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Failure of a push to Loki.
+#[derive(Debug)]
+pub enum Error {
+    /// The request body could not be encoded.
+    Serialize(serde_json::Error),
+    /// The encoded body could not be compressed.
+    Compression(Box<dyn std::error::Error + Send + Sync>),
+    /// Loki could not be reached.
+    Connect(String),
+    /// The request or response timed out.
+    Timeout(String),
+    /// Loki answered with an error status.
+    Http { status: u16, body: String },
+    /// Loki answered 429, optionally with a `Retry-After` delay.
+    RateLimited { retry_after: Option<Duration> },
+    /// The endpoint or settings can never work.
+    InvalidConfig(String),
+}
+
+impl Error {
+    /// Whether sending the same request again may succeed.
+    pub fn is_retryable(&self)->bool{
+        match self {
+            Error::Connect(_) | Error::Timeout(_) | Error::RateLimited { .. } => true,
+            Error::Http { status, .. } => *status >= 500 || *status == 408,
+            Error::Serialize(_) | Error::Compression(_) | Error::InvalidConfig(_) => false,
         }
     }
-}
\ No newline at end of file
+
+    /// HTTP status returned by Loki, if it answered at all.
+    pub fn status(&self)->Option<u16>{
+        match self {
+            Error::Http { status, .. } => Some(*status),
+            Error::RateLimited { .. } => Some(429),
+            _ => None,
+        }
+    }
+
+    pub fn retry_after(&self)->Option<Duration>{
+        match self {
+            Error::RateLimited { retry_after } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f:&mut fmt::Formatter)->fmt::Result{
+        match self {
+            Error::Serialize(e) => write!(f, "serialization error: {}", e),
+            Error::Compression(e) => write!(f, "compression error: {}", e),
+            Error::Connect(e) => write!(f, "connection error: {}", e),
+            Error::Timeout(e) => write!(f, "timeout: {}", e),
+            Error::Http { status, body } => write!(f, "request failed with status {}: '{}'", status, body),
+            Error::RateLimited { retry_after: Some(delay) } => write!(f, "rate limited, retry after {:?}", delay),
+            Error::RateLimited { retry_after: None } => write!(f, "rate limited"),
+            Error::InvalidConfig(e) => write!(f, "invalid config: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self)->Option<&(dyn std::error::Error + 'static)>{
+        match self {
+            Error::Serialize(e) => Some(e),
+            Error::Compression(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err:serde_json::Error)->Self{
+        Error::Serialize(err)
+    }
+}
+
+impl From<&ureq::Error> for Error {
+    fn from(err:&ureq::Error)->Self{
+        match err {
+            ureq::Error::BadUrl(_) | ureq::Error::UnknownScheme(_) => Error::InvalidConfig(err.body_text()),
+            ureq::Error::Io(e) if e.kind() == std::io::ErrorKind::TimedOut || e.kind() == std::io::ErrorKind::WouldBlock => Error::Timeout(err.body_text()),
+            _ => Error::Connect(err.body_text()),
+        }
+    }
+}
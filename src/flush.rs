@@ -0,0 +1,96 @@
+use std::sync::{Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+pub struct FlushTrigger {
+    entries: AtomicUsize,
+    bytes: AtomicUsize,
+    max_entries: AtomicUsize,
+    max_bytes: AtomicUsize,
+    pending: Mutex<bool>,
+    cond: Condvar,
+}
+
+impl Default for FlushTrigger {
+    fn default() -> Self {
+        FlushTrigger {
+            entries: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+            max_entries: AtomicUsize::new(0),
+            max_bytes: AtomicUsize::new(0),
+            pending: Mutex::new(false),
+            cond: Condvar::new(),
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl FlushTrigger {
+    pub fn new()->Self{
+        FlushTrigger::default()
+    }
+
+    pub fn set_limits(&self, max_entries:usize, max_bytes:usize){
+        self.max_entries.store(max_entries, Ordering::Relaxed);
+        self.max_bytes.store(max_bytes, Ordering::Relaxed);
+    }
+
+    pub fn entries(&self)->usize{
+        self.entries.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes(&self)->usize{
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn add(&self, entries:usize, bytes:usize){
+        let prev_entries = self.entries.fetch_add(entries, Ordering::Relaxed);
+        let prev_bytes = self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        if crossed(prev_entries, entries, self.max_entries.load(Ordering::Relaxed)) ||
+            crossed(prev_bytes, bytes, self.max_bytes.load(Ordering::Relaxed)) {
+            self.notify();
+        }
+    }
+
+    pub fn sub(&self, entries:usize, bytes:usize){
+        self.entries.fetch_sub(entries, Ordering::Relaxed);
+        self.bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    pub fn notify(&self){
+        *self.pending.lock().unwrap() = true;
+        self.cond.notify_all();
+    }
+
+    pub fn wait(&self, timeout:Duration)->bool{
+        let deadline = Instant::now() + timeout;
+        let mut pending = self.pending.lock().unwrap();
+        while !*pending {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            pending = self.cond.wait_timeout(pending, deadline - now).unwrap().0;
+        }
+        *pending = false;
+        true
+    }
+
+    /// Sleeps for `timeout`, waking early on `notify` once `cancellation`
+    /// is set. Unlike `wait` it leaves a pending flush for the next `wait`.
+    pub fn sleep(&self, timeout:Duration, cancellation:&AtomicBool){
+        let deadline = Instant::now() + timeout;
+        let mut pending = self.pending.lock().unwrap();
+        while !cancellation.load(Ordering::Relaxed) {
+            let now = Instant::now();
+            if now >= deadline {
+                return;
+            }
+            pending = self.cond.wait_timeout(pending, deadline - now).unwrap().0;
+        }
+    }
+}
+
+fn crossed(prev:usize, added:usize, limit:usize)->bool{
+    limit > 0 && prev < limit && prev + added >= limit
+}
@@ -0,0 +1,181 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::fmt::Write;
+use serde_json::{Map, Value};
+
+use crate::models::LogMessage;
+
+pub type Field = (&'static str, FieldValue);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldValue {
+    Str(Cow<'static, str>),
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Bool(bool),
+}
+
+impl fmt::Display for FieldValue {
+    fn fmt(&self, f:&mut fmt::Formatter<'_>)->fmt::Result{
+        match self {
+            FieldValue::Str(v) => f.write_str(v),
+            FieldValue::I64(v) => write!(f, "{}", v),
+            FieldValue::U64(v) => write!(f, "{}", v),
+            FieldValue::F64(v) => write!(f, "{}", v),
+            FieldValue::Bool(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+impl From<&'static str> for FieldValue {
+    fn from(v:&'static str)->Self{ FieldValue::Str(Cow::Borrowed(v)) }
+}
+impl From<String> for FieldValue {
+    fn from(v:String)->Self{ FieldValue::Str(Cow::Owned(v)) }
+}
+impl From<i64> for FieldValue {
+    fn from(v:i64)->Self{ FieldValue::I64(v) }
+}
+impl From<i32> for FieldValue {
+    fn from(v:i32)->Self{ FieldValue::I64(v.into()) }
+}
+impl From<u64> for FieldValue {
+    fn from(v:u64)->Self{ FieldValue::U64(v) }
+}
+impl From<u32> for FieldValue {
+    fn from(v:u32)->Self{ FieldValue::U64(v.into()) }
+}
+impl From<u16> for FieldValue {
+    fn from(v:u16)->Self{ FieldValue::U64(v.into()) }
+}
+impl From<usize> for FieldValue {
+    fn from(v:usize)->Self{ FieldValue::U64(v as u64) }
+}
+impl From<f64> for FieldValue {
+    fn from(v:f64)->Self{ FieldValue::F64(v) }
+}
+impl From<bool> for FieldValue {
+    fn from(v:bool)->Self{ FieldValue::Bool(v) }
+}
+
+impl From<&FieldValue> for Value {
+    fn from(v:&FieldValue)->Self{
+        match v {
+            FieldValue::Str(v) => Value::String(v.to_string()),
+            FieldValue::I64(v) => Value::from(*v),
+            FieldValue::U64(v) => Value::from(*v),
+            FieldValue::F64(v) => Value::from(*v),
+            FieldValue::Bool(v) => Value::Bool(*v),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Formatter {
+    #[default]
+    Plain,
+    Json,
+    Logfmt,
+    Template(String),
+}
+
+impl Formatter {
+    pub fn render(&self, message:&mut LogMessage){
+        if message.fields.is_empty() && *self == Formatter::Plain {
+            return;
+        }
+        let line = match self {
+            Formatter::Plain => render_plain(message),
+            Formatter::Json => render_json(message),
+            Formatter::Logfmt => render_logfmt(message),
+            Formatter::Template(template) => render_template(template, message),
+        };
+        message.message = line;
+        message.fields.clear();
+    }
+}
+
+fn render_plain(message:&LogMessage)->String{
+    let mut line = message.message.clone();
+    for (key, value) in message.fields.iter() {
+        line.push(' ');
+        write_logfmt_pair(&mut line, key, &value.to_string());
+    }
+    line
+}
+
+fn render_json(message:&LogMessage)->String{
+    let mut map = Map::new();
+    map.insert("level".into(), Value::String(message.level.as_str().into()));
+    map.insert("msg".into(), Value::String(message.message.clone()));
+    for (key, value) in message.fields.iter() {
+        map.insert((*key).into(), value.into());
+    }
+    Value::Object(map).to_string()
+}
+
+fn render_logfmt(message:&LogMessage)->String{
+    let mut line = String::with_capacity(message.message.len() + 32);
+    write_logfmt_pair(&mut line, "level", message.level.as_str());
+    line.push(' ');
+    write_logfmt_pair(&mut line, "msg", &message.message);
+    for (key, value) in message.fields.iter() {
+        line.push(' ');
+        write_logfmt_pair(&mut line, key, &value.to_string());
+    }
+    line
+}
+
+fn render_template(template:&str, message:&LogMessage)->String{
+    expand_template(template, message.message.len(), |name, result|match name {
+        "msg" => result.push_str(&message.message),
+        "level" => result.push_str(message.level.as_str()),
+        name => if let Some((_, value)) = message.fields.iter().find(|(k, _)|*k == name) {
+            let _ = write!(result, "{}", value);
+        }
+    })
+}
+
+/// Copies `template`, letting `write` fill in each `{name}` placeholder.
+/// An unclosed `{` is copied as is. Shared by the `Template` formatter and
+/// the pipeline's template stage.
+pub(crate) fn expand_template<F>(template:&str, extra:usize, mut write:F)->String
+    where F:FnMut(&str, &mut String)
+{
+    let mut result = String::with_capacity(template.len() + extra);
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        let end = match rest[start..].find('}') {
+            None => {
+                rest = &rest[start..];
+                break;
+            },
+            Some(end) => start + end
+        };
+        write(&rest[start + 1..end], &mut result);
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    result
+}
+
+fn write_logfmt_pair(line:&mut String, key:&str, value:&str){
+    line.push_str(key);
+    line.push('=');
+    if !value.is_empty() && !value.contains(|c:char|c.is_whitespace() || c == '"' || c == '=') {
+        line.push_str(value);
+        return;
+    }
+    line.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
@@ -0,0 +1,53 @@
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+
+use crate::log::{LogContainer, LogMetric};
+use crate::sender::LogSender;
+use crate::models::{LogMetricConf, LogMetricConfBuilder};
+
+pub trait LokiLabels {
+    fn label_names()->&'static [&'static str];
+    fn label_values(&self)->Vec<String>;
+
+    fn conf_builder()->LogMetricConfBuilder{
+        LogMetricConfBuilder::new().add_labels(Self::label_names())
+    }
+
+    fn conf()->LogMetricConf{
+        Self::conf_builder().build()
+    }
+}
+
+pub struct TypedContainer<T:?Sized> {
+    container: Arc<LogContainer>,
+    _labels: PhantomData<fn(&T)>,
+}
+
+impl<T:?Sized> Clone for TypedContainer<T> {
+    fn clone(&self)->Self{
+        TypedContainer { container: self.container.clone(), _labels: PhantomData }
+    }
+}
+
+impl<T:LokiLabels+?Sized> TypedContainer<T> {
+    pub fn new(container:Arc<LogContainer>)->Self{
+        assert_eq!(container.config().get_label_names().as_slice(), T::label_names());
+        TypedContainer { container, _labels: PhantomData }
+    }
+
+    pub fn get(&self, labels:&T)->Arc<Mutex<LogMetric>>{
+        let values = labels.label_values();
+        let values:Vec<&str> = values.iter().map(|v|v.as_str()).collect();
+        self.container.get(&values)
+    }
+
+    pub fn sender(&self, labels:&T)->LogSender{
+        let values = labels.label_values();
+        let values:Vec<&str> = values.iter().map(|v|v.as_str()).collect();
+        self.container.sender(&values)
+    }
+
+    pub fn container(&self)->&Arc<LogContainer>{
+        &self.container
+    }
+}
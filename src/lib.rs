@@ -1,5 +1,4 @@
-#[macro_use]
-extern crate error_chain;
+extern crate self as log_loki;
 #[macro_use]
 extern crate lazy_static;
 extern crate fnv;
@@ -7,25 +6,200 @@ extern crate chrono;
 //extern crate minreq;
 extern crate ureq;
 extern crate snap;
+extern crate regex;
+extern crate serde_json;
+#[macro_use]
+mod macros;
+mod config;
+mod context;
+mod dedup;
 mod errors;
+mod flush;
+mod format;
+mod labels;
+mod listeners;
 mod models;
 mod log;
+mod metrics;
+mod pipeline;
+mod redact;
 mod loki;
 mod scrape;
+mod sender;
+mod status;
 mod util;
 
-pub use crate::models::{LogMetricConfBuilder, LogMetricConf};
-pub use crate::scrape::{Scrape, ScrapeEvents};
-pub use crate::loki::{LokiScrapeConfig};
-pub use crate::log::{LogContainer,LogMetric};
+pub use crate::models::{LogMetricConfBuilder, LogMetricConf, Level, RateLimit, Sampling, Dedup, Multiline, LinePolicy, Metadata, Location, LogMessage};
+pub use crate::scrape::{Scrape, ScrapeEvents, ScrapeReport, Backlog};
+pub use crate::loki::{LokiScrapeConfig, PushFormat, Compression, Rejection};
+pub use crate::log::{LogContainer,LogMetric,StreamMap,Suppressed};
+pub use crate::sender::LogSender;
+pub use crate::metrics::{Registry, DropReason, StreamStats};
+pub use crate::status::Health;
+pub use crate::errors::Error;
+pub use crate::config::Config;
+pub use crate::listeners::{StderrListener, CountingListener};
+#[doc(hidden)]
+pub use crate::macros::LogTarget;
+pub use crate::redact::{Redactor, BuiltinRule};
+pub use crate::pipeline::{Pipeline, TimestampFormat};
+pub use crate::format::{Field, FieldValue, Formatter};
+pub use crate::context::{TraceContext, ContextProvider, ContextMode};
+pub use crate::labels::{LokiLabels, TypedContainer};
+#[cfg(feature = "derive")]
+pub use log_loki_derive::LokiLabels;
+#[cfg(feature = "opentelemetry")]
+pub use crate::context::OpenTelemetryContext;
+#[cfg(feature = "tracing")]
+pub use crate::context::TracingContext;
+
+#[doc(hidden)]
+pub mod bench {
+    pub use crate::loki::{LokiStream, LokiEntry, ProtoEncoder, logproto};
+}
 
 #[cfg(test)]
 mod tests {
-    use crate::loki::{LokiStream, LokiModel, LokiScrapeConfig};
-    use crate::scrape::Scrape;
-    use std::time::Duration;
-    use crate::models::LogMetricConfBuilder;
-    use crate::log::Log;
+    use crate::loki::{LokiStream, LokiEntry, LokiModel, LokiScrapeConfig, PushFormat, Compression, ProtoEncoder, Rejection, logproto};
+    use crate::scrape::{Scrape, ScrapeConfig, ScrapeEvents, ScrapeProcess, ScrapeReport, Backlog};
+    use crate::listeners::CountingListener;
+    use crate::config::Config;
+    use std::time::{Duration, Instant};
+    use std::sync::{Arc, Mutex, mpsc};
+    use crate::models::{LogMetricConfBuilder, Level, Dedup, Multiline, LinePolicy, LogMessage};
+    use crate::log::{Log, LogMetric, Suppressed};
+    use crate::redact::{Redactor, BuiltinRule};
+    use crate::pipeline::{Pipeline, TimestampFormat};
+    use crate::format::Formatter;
+    use crate::context::{TraceContext, ContextMode};
+    use crate::labels::LokiLabels;
+    use crate::metrics::{Registry, DropReason};
+    use crate::errors::*;
+
+    struct ChannelScrapeProcess(mpsc::Sender<usize>);
+    impl ScrapeProcess for ChannelScrapeProcess {
+        fn send(&mut self, items: std::slice::Iter<'_, Arc<Mutex<LogMetric>>>)->Result<ScrapeReport>{
+            let mut size = 0;
+            for metric in items {
+                let mut m = metric.lock().unwrap();
+                while m.pop().is_some() {
+                    size += 1;
+                }
+            }
+            if size > 0 {
+                self.0.send(size).unwrap();
+            }
+            Ok(size.into())
+        }
+    }
+
+    // Reads one HTTP request, returning its headers and body.
+    fn read_request(reader:&mut std::io::BufReader<std::net::TcpStream>)->(String, Vec<u8>){
+        use std::io::{BufRead, Read};
+        let mut headers = String::new();
+        while reader.read_line(&mut headers).unwrap() > 2 {}
+        let length = headers.lines()
+            .find_map(|l|l.to_ascii_lowercase().strip_prefix("content-length:").map(|v|v.trim().parse::<usize>().unwrap()))
+            .unwrap_or(0);
+        let mut body = vec![0u8; length];
+        reader.read_exact(&mut body).unwrap();
+        (headers, body)
+    }
+
+    // Accepts one HTTP request and answers 204, returning its headers and body.
+    fn capture_request(listener:std::net::TcpListener)->std::thread::JoinHandle<(String, Vec<u8>)>{
+        std::thread::spawn(move||{
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = std::io::BufReader::new(stream);
+            let request = read_request(&mut reader);
+            std::io::Write::write_all(reader.get_mut(), b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n").unwrap();
+            request
+        })
+    }
+
+    // Answers each request with the next status line and body, returning the request bodies.
+    fn serve_responses(listener:std::net::TcpListener, responses:Vec<(&'static str, &'static str)>)->std::thread::JoinHandle<Vec<Vec<u8>>>{
+        std::thread::spawn(move||{
+            responses.iter().map(|(status, body)|{
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = std::io::BufReader::new(stream);
+                let (_, request) = read_request(&mut reader);
+                let response = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body);
+                std::io::Write::write_all(reader.get_mut(), response.as_bytes()).unwrap();
+                request
+            }).collect()
+        })
+    }
+
+    struct ChannelScrapeConfig {
+        sender: Mutex<mpsc::Sender<usize>>,
+        flush_entries: usize,
+    }
+    impl ScrapeConfig for ChannelScrapeConfig {
+        type ScrapeType = ChannelScrapeProcess;
+        fn get_scrape_interval(&self)->Duration{ Duration::from_secs(60) }
+        fn get_scrape_process(&self)->Self::ScrapeType{ ChannelScrapeProcess(self.sender.lock().unwrap().clone()) }
+        fn get_flush_entries(&self)->usize{ self.flush_entries }
+    }
+
+    #[test]
+    fn flush_trigger_test(){
+        let (tx, rx) = mpsc::channel();
+        let scrape = Scrape::new();
+        let container = scrape.get(LogMetricConfBuilder::new().add_labels(&["flush"]).set_flush_level(Level::Error).build());
+        let metric = container.get(&["1"]);
+        scrape.start(ChannelScrapeConfig{ sender: Mutex::new(tx), flush_entries: 3 });
+
+        let start = Instant::now();
+        metric.lock().unwrap().push("message1".to_string());
+        metric.lock().unwrap().push("message2".to_string());
+        metric.lock().unwrap().push("message3".to_string());
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 3);
+
+        metric.lock().unwrap().push_with_level(Level::Error, "error".to_string());
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 1);
+        assert!(start.elapsed() < Duration::from_secs(60));
+
+        scrape.stop();
+    }
+
+    #[test]
+    fn flush_level_test(){
+        let (tx, rx) = mpsc::channel();
+        let scrape = Scrape::new();
+        let container = scrape.get(LogMetricConfBuilder::new().add_labels(&["flush_level"]).set_flush_level(Level::Warn).build());
+        let metric = container.get(&["1"]);
+        scrape.start(ChannelScrapeConfig{ sender: Mutex::new(tx), flush_entries: 0 });
+
+        metric.lock().unwrap().push_with_level(Level::Info, "info".to_string());
+        assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+
+        let start = Instant::now();
+        metric.lock().unwrap().push_with_level(Level::Warn, "warn".to_string());
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 2);
+        assert!(start.elapsed() < Duration::from_secs(60));
+
+        scrape.stop();
+    }
+
+    #[test]
+    fn sender_flush_level_test(){
+        let (tx, rx) = mpsc::channel();
+        let scrape = Scrape::new();
+        let container = scrape.get(LogMetricConfBuilder::new().add_labels(&["sender_flush_level"]).set_flush_level(Level::Error).build());
+        let sender = container.sender(&["1"]);
+        scrape.start(ChannelScrapeConfig{ sender: Mutex::new(tx), flush_entries: 0 });
+
+        sender.push(Level::Info, "info");
+        assert!(rx.recv_timeout(Duration::from_millis(500)).is_err());
+
+        let start = Instant::now();
+        sender.push(Level::Error, "error");
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 2);
+        assert!(start.elapsed() < Duration::from_secs(60));
+
+        scrape.stop();
+    }
 
     #[test]
     fn scrape_loki_test(){
@@ -34,7 +208,7 @@ mod tests {
         let log_conf = LogMetricConfBuilder::new().add_labels(&["one","two"]).build();
         let metrics = scrape.get(log_conf);
         {
-            let mut container = metrics.lock().unwrap();
+            let container = &metrics;
             let metric = container.get(&["1", "2"]);
             {
                 let mut m = metric.lock().unwrap();
@@ -48,13 +222,962 @@ mod tests {
         std::thread::sleep(Duration::from_secs(10));
     }
 
+    #[test]
+    fn priority_lanes_test(){
+        let metric = Log::get(LogMetricConfBuilder::new().add_labels(&["lanes"]).set_default_capacity(4).reserve_capacity(Level::Error, 1).build())
+            .get(&["1"]);
+        let mut m = metric.lock().unwrap();
+        for i in 0..5 {
+            let _ = m.push_with_level(Level::Debug, format!("debug{}", i));
+        }
+        assert_eq!(m.len_level(Level::Debug), 3);
+        assert_eq!(m.dropped(Level::Debug), 2);
+
+        assert!(m.push_with_level(Level::Error, "error1".to_string()).is_some());
+        assert!(m.push_with_level(Level::Error, "error2".to_string()).is_none());
+        assert_eq!(m.dropped(Level::Error), 1);
+        assert_eq!(m.dropped_total(), 3);
+
+        assert_eq!(m.pop_level(Level::Error).unwrap().message, "error1");
+        assert_eq!(m.pop().unwrap().message, "debug0");
+    }
+
+    #[test]
+    fn rate_limit_and_sampling_test(){
+        let sampled = Log::get(LogMetricConfBuilder::new().add_labels(&["sampled"]).set_sample_every(3).build())
+            .get(&["1"]);
+        let mut m = sampled.lock().unwrap();
+        let accepted = (0..9).filter(|i|m.push(format!("message{}", i)).is_some()).count();
+        assert_eq!(accepted, 3);
+        assert_eq!(m.suppressed().sampled, 6);
+
+        let limited = Log::get(LogMetricConfBuilder::new().add_labels(&["limited"]).set_rate_limit(0.001, 2).build())
+            .get(&["1"]);
+        let mut m = limited.lock().unwrap();
+        let accepted = (0..5).filter(|i|m.push(format!("message{}", i)).is_some()).count();
+        assert_eq!(accepted, 2);
+        assert_eq!(m.flush_suppressed().unwrap().rate_limited, 3);
+        assert_eq!(m.len(), 3);
+        assert!(m.flush_suppressed().is_none());
+    }
+
+    #[test]
+    fn admission_capacity_test(){
+        // A line dropped for capacity doesn't spend a rate limit token.
+        let metric = Log::get(LogMetricConfBuilder::new().add_labels(&["admission_capacity"]).set_default_capacity(1).set_rate_limit(0.001, 2).build())
+            .get(&["1"]);
+        let mut m = metric.lock().unwrap();
+        assert!(m.push("kept".to_string()).is_some());
+        assert!(m.push("full".to_string()).is_none());
+        assert_eq!(m.dropped(Level::Info), 1);
+        assert!(m.pop().is_some());
+        assert!(m.push("after".to_string()).is_some());
+        assert_eq!(m.suppressed().rate_limited, 0);
+        drop(m);
+
+        let container = Log::get(LogMetricConfBuilder::new().add_labels(&["admission_inbox"]).set_default_capacity(1).set_sample_every(2).build());
+        let sender = container.sender(&["1"]);
+        assert!(sender.push(Level::Info, "kept".to_string()).is_some());
+        assert!(sender.push(Level::Info, "full".to_string()).is_none());
+        let metric = container.get(&["1"]);
+        assert_eq!(metric.lock().unwrap().collect_pending(), 1);
+        assert!(metric.lock().unwrap().pop().is_some());
+        // The next line is the second one sampling saw, not the third.
+        assert!(sender.push(Level::Info, "sampled".to_string()).is_none());
+        assert!(sender.push(Level::Info, "after".to_string()).is_some());
+    }
+
+    #[test]
+    fn dedup_test(){
+        let metric = Log::get(LogMetricConfBuilder::new().add_labels(&["dedup"]).set_dedup(Dedup::Consecutive).build())
+            .get(&["1"]);
+        {
+            let mut m = metric.lock().unwrap();
+            for message in &["a", "a", "a", "b", "a"] {
+                m.push(message.to_string());
+            }
+        }
+        let stream = LokiStream::drain(&mut metric.lock().unwrap()).remove(0);
+        assert_eq!(stream.len(), 3);
+        assert!(stream.entries[0].line.starts_with("a (repeated 3 times"));
+        assert_eq!(stream.entries[1].line, "b");
+        assert_eq!(stream.entries[2].line, "a");
+
+        let window = Log::get(LogMetricConfBuilder::new().add_labels(&["dedup_window"]).set_dedup(Dedup::Window).build())
+            .get(&["1"]);
+        {
+            let mut m = window.lock().unwrap();
+            for message in &["a", "b", "a", "b", "c"] {
+                m.push(message.to_string());
+            }
+        }
+        let stream = LokiStream::drain(&mut window.lock().unwrap()).remove(0);
+        assert_eq!(stream.len(), 3);
+        assert!(stream.entries[1].line.starts_with("b (repeated 2 times"));
+    }
+
+    #[test]
+    fn multiline_test(){
+        let multiline = Multiline::with_first_line(regex::Regex::new(r"^\S").unwrap())
+            .set_max_lines(3)
+            .set_max_wait(Duration::from_secs(60));
+        let metric = Log::get(LogMetricConfBuilder::new().add_labels(&["multiline"]).set_multiline(multiline).build())
+            .get(&["1"]);
+        let mut m = metric.lock().unwrap();
+        for line in &["panic", "  at a", "  at b", "  at c", "next", "  at d"] {
+            m.push(line.to_string());
+        }
+        assert_eq!(m.len(), 3);
+        assert_eq!(m.pop().unwrap().message, "panic\n  at a\n  at b");
+        assert_eq!(m.pop().unwrap().message, "  at c");
+        assert!(m.pop().is_none());
+        assert_eq!(m.len(), 1);
+    }
+
+    #[test]
+    fn multiline_shutdown_test(){
+        let (tx, rx) = mpsc::channel();
+        let multiline = Multiline::with_first_line(regex::Regex::new(r"^\S").unwrap())
+            .set_max_wait(Duration::from_secs(60));
+        let scrape = Scrape::new();
+        let metric = scrape.get(LogMetricConfBuilder::new().add_labels(&["multiline_shutdown"]).set_multiline(multiline).build())
+            .get(&["1"]);
+        scrape.start(ChannelScrapeConfig{ sender: Mutex::new(tx), flush_entries: 0 });
+        metric.lock().unwrap().push("panic: boom".to_string());
+        metric.lock().unwrap().push("  at frame".to_string());
+
+        // The message is younger than max_wait, but stopping must not leave it behind.
+        scrape.stop();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 1);
+        assert_eq!(metric.lock().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn multiline_limits_test(){
+        let multiline = Multiline::with_first_line(regex::Regex::new(r"^\S").unwrap());
+        let metric = Log::get(LogMetricConfBuilder::new().add_labels(&["multiline_limits"]).set_multiline(multiline).set_rate_limit(0.0, 2).build())
+            .get(&["1"]);
+        let mut m = metric.lock().unwrap();
+        assert!(m.push("panic".to_string()).is_some());
+        assert!(m.push("  at a".to_string()).is_some());
+        // Out of tokens: the line is never formatted and its continuations go with it.
+        assert!(m.push_lazy(||->String{ panic!("formatted a rate limited line") }).is_none());
+        assert!(m.push("  at b".to_string()).is_none());
+        assert_eq!(m.suppressed().rate_limited, 2);
+        assert_eq!(m.pop().unwrap().message, "panic\n  at a");
+    }
+
+    #[test]
+    fn redaction_test(){
+        let redactor = Arc::new(Redactor::new()
+            .add_builtin(BuiltinRule::Email)
+            .add_builtin(BuiltinRule::BearerToken)
+            .add_hash_rule("user", regex::Regex::new(r"user=\w+").unwrap()));
+        let metric = Log::get(LogMetricConfBuilder::new().add_labels(&["redact"]).set_redactor(redactor.clone()).build())
+            .get(&["1"]);
+        {
+            let mut m = metric.lock().unwrap();
+            m.push("mail to john@example.com with Bearer abc.def".to_string());
+            m.push("login user=john".to_string());
+            m.push("login user=john".to_string());
+        }
+        let stream = LokiStream::drain(&mut metric.lock().unwrap()).remove(0);
+        assert_eq!(stream.entries[0].line, "mail to <email> with <bearer_token>");
+        assert!(stream.entries[1].line.starts_with("login user:"));
+        assert_eq!(stream.entries[1].line, stream.entries[2].line);
+        assert_eq!(redactor.count("email"), 1);
+        assert_eq!(redactor.count("user"), 2);
+
+        let hashed = |key:&str|Redactor::new().add_builtin_hashed(BuiltinRule::Email).set_hash_key(key).redact("john@example.com").into_owned();
+        assert_eq!(hashed("secret"), hashed("secret"));
+        assert_ne!(hashed("secret"), hashed("other"));
+    }
+
+    #[test]
+    fn redaction_metadata_test(){
+        // Metadata is redacted too; pipeline labels are taken from the redacted line.
+        let redactor = Arc::new(Redactor::new().add_builtin(BuiltinRule::Email));
+        let pipeline = Pipeline::new()
+            .logfmt(&["user"])
+            .labels(&[("user", "user")]);
+        let metric = Log::get(LogMetricConfBuilder::new().add_labels(&["redact_metadata"]).set_redactor(redactor).set_pipeline(pipeline).build())
+            .get(&["1"]);
+        metric.lock().unwrap().push_with_metadata(Level::Info, "user=john@example.com", vec![("email".to_string(), "ann@example.com".to_string())]);
+        let stream = LokiStream::drain(&mut metric.lock().unwrap()).remove(0);
+        assert_eq!(stream.entries[0].line, "user=<email>");
+        assert_eq!(stream.entries[0].metadata, vec![("email".to_string(), "<email>".to_string())]);
+        assert!(stream.labels.contains(&("user".to_string(), "<email>".to_string())), "{:?}", stream.labels);
+    }
+
+    #[test]
+    fn pipeline_test(){
+        let pipeline = Pipeline::new()
+            .drop(regex::Regex::new("healthcheck").unwrap())
+            .logfmt(&["level", "msg", "ts"])
+            .json_from("msg", &[("user", "user.name")])
+            .regex(regex::Regex::new(r"took=(?P<took>\d+)ms").unwrap())
+            .labels(&[("level", "level")])
+            .timestamp("ts", TimestampFormat::UnixMs)
+            .template("{user}: took {took}ms");
+        let metric = Log::get(LogMetricConfBuilder::new().add_labels(&["pipeline"]).set_pipeline(pipeline).build())
+            .get(&["1"]);
+        {
+            let mut m = metric.lock().unwrap();
+            m.push(r#"level=info ts=1000 msg="{\"user\":{\"name\":\"bob\"}}" took=12ms"#.to_string());
+            m.push("level=info msg=healthcheck".to_string());
+            m.push(r#"level=error ts=2000 msg="{\"user\":{\"name\":\"ann\"}}" took=7ms"#.to_string());
+        }
+        let streams = LokiStream::drain(&mut metric.lock().unwrap());
+        assert_eq!(streams.len(), 2);
+        assert_eq!(streams[0].selector(), "{pipeline=\"1\",level=\"info\"}");
+        assert_eq!(streams[0].entries[0].line, "bob: took 12ms");
+        assert_eq!(streams[0].entries[0].ts, std::time::UNIX_EPOCH + Duration::from_secs(1));
+        assert_eq!(streams[1].selector(), "{pipeline=\"1\",level=\"error\"}");
+        assert_eq!(streams[1].entries[0].line, "ann: took 7ms");
+    }
+
+    #[test]
+    fn pipeline_label_override_test(){
+        let pipeline = Pipeline::new()
+            .logfmt(&["pipeline_override"])
+            .labels(&[("pipeline_override", "pipeline_override")]);
+        let metric = Log::get(LogMetricConfBuilder::new().add_labels(&["pipeline_override"]).set_pipeline(pipeline).build())
+            .get(&["1"]);
+        metric.lock().unwrap().push("pipeline_override=2 hello".to_string());
+        let streams = LokiStream::drain(&mut metric.lock().unwrap());
+        assert_eq!(streams[0].selector(), "{pipeline_override=\"2\"}");
+        assert_eq!(streams[0].labels, vec![("pipeline_override".to_string(), "2".to_string())]);
+    }
+
+    #[test]
+    fn pipeline_timestamp_overflow_test(){
+        let pipeline = Pipeline::new()
+            .logfmt(&["ts"])
+            .timestamp("ts", TimestampFormat::Unix);
+        let metric = Log::get(LogMetricConfBuilder::new().add_labels(&["pipeline_overflow"]).set_pipeline(pipeline).build())
+            .get(&["1"]);
+        let start = std::time::SystemTime::now();
+        {
+            let mut m = metric.lock().unwrap();
+            m.push("ts=1e300 hello".to_string());
+            m.push("ts=inf hello".to_string());
+        }
+        let streams = LokiStream::drain(&mut metric.lock().unwrap());
+        assert_eq!(streams[0].entries.len(), 2);
+        assert!(streams[0].entries.iter().all(|e|e.ts >= start));
+    }
+
+    #[test]
+    fn max_line_size_test(){
+        let get = |name:&str, policy:LinePolicy|{
+            Log::get(LogMetricConfBuilder::new().add_labels(&[name]).set_max_line_size(20, policy).build())
+                .get(&["1"])
+        };
+        let line = "0123456789abcdefghijklmnopqrstuvwxyz".to_string();
+
+        let truncated = get("truncate", LinePolicy::Truncate);
+        let mut m = truncated.lock().unwrap();
+        m.push(line.clone());
+        m.push("short".to_string());
+        assert_eq!(m.oversized(), 1);
+        assert_eq!(m.pop().unwrap().message, "012345...[truncated]");
+
+        let split = get("split", LinePolicy::Split);
+        let mut m = split.lock().unwrap();
+        m.push(line.clone());
+        assert_eq!(m.len(), 2);
+        assert_eq!(m.pop().unwrap().message, "0123456789abcdefghij");
+        assert_eq!(m.pop().unwrap().message, "klmnopqrstuvwxyz");
+
+        let dropped = get("drop", LinePolicy::Drop);
+        let mut m = dropped.lock().unwrap();
+        assert!(m.push(line).is_none());
+        assert!(m.is_empty());
+        assert_eq!(m.oversized(), 1);
+    }
+
+    #[test]
+    fn max_line_size_rendered_test(){
+        let metric = Log::get(LogMetricConfBuilder::new().add_labels(&["max_line_rendered"]).set_max_line_size(30, LinePolicy::Truncate).build())
+            .get(&["1"]);
+        let mut m = metric.lock().unwrap();
+        m.push_fields(Level::Info, "short", vec![("user", "0123456789abcdefghijklmnopqrstuvwxyz".into())]);
+        let stream = LokiStream::drain(&mut m).remove(0);
+        assert_eq!(stream.entries[0].line, "short user=01234...[truncated]");
+        assert_eq!(m.oversized(), 1);
+
+        let metric = Log::get(LogMetricConfBuilder::new().add_labels(&["max_line_json"]).set_max_line_size(20, LinePolicy::Drop).set_formatter(Formatter::Json).build())
+            .get(&["1"]);
+        let mut m = metric.lock().unwrap();
+        m.push("fits before json".to_string());
+        assert!(LokiStream::drain(&mut m).is_empty());
+        assert_eq!(m.take_drops()[DropReason::Oversized as usize], 1);
+    }
+
+    #[test]
+    fn formatter_test(){
+        let get = |name:&str, formatter:Formatter|{
+            Log::get(LogMetricConfBuilder::new().add_labels(&[name]).set_formatter(formatter).build())
+                .get(&["1"])
+        };
+        let push = |metric:&Arc<Mutex<LogMetric>>|{
+            metric.lock().unwrap().push_fields(Level::Warn, "slow request", vec![("path", "/a b".into()), ("took", 12u64.into()), ("ok", true.into())]);
+            LokiStream::drain(&mut metric.lock().unwrap()).remove(0).entries.remove(0).line
+        };
+
+        assert_eq!(push(&get("format_json", Formatter::Json)), r#"{"level":"warn","msg":"slow request","ok":true,"path":"/a b","took":12}"#);
+        assert_eq!(push(&get("format_logfmt", Formatter::Logfmt)), r#"level=warn msg="slow request" path="/a b" took=12 ok=true"#);
+        assert_eq!(push(&get("format_plain", Formatter::Plain)), r#"slow request path="/a b" took=12 ok=true"#);
+        assert_eq!(push(&get("format_template", Formatter::Template("[{level}] {msg} in {took}ms".into()))), "[warn] slow request in 12ms");
+    }
+
+    #[test]
+    fn macros_test(){
+        let metric = Log::get(LogMetricConfBuilder::new().add_labels(&["macros"]).set_min_level(Level::Info).set_capture_location(true).build())
+            .get(&["1"]);
+        let formatted = std::cell::Cell::new(0);
+        let count = ||{ formatted.set(formatted.get() + 1); formatted.get() };
+
+        assert!(loki_debug!(metric, "skipped {}", count()).is_none());
+        assert!(loki_warn!(&metric, "kept {}", count()).is_some());
+        assert_eq!(formatted.get(), 1);
+
+        let mut streams = LokiStream::drain(&mut metric.lock().unwrap());
+        let line = streams.remove(0).entries.remove(0).line;
+        assert!(line.starts_with(&format!("kept 1 file={} line=", file!())), "{}", line);
+        assert!(line.ends_with(&format!("module={}", module_path!())), "{}", line);
+    }
+
+    #[test]
+    fn sender_test(){
+        let container = Log::get(LogMetricConfBuilder::new().add_labels(&["sender"]).set_default_capacity(1000).build());
+        let sender = container.sender(&["1"]);
+        let workers:Vec<_> = (0..4).map(|t|{
+            let sender = sender.clone();
+            std::thread::spawn(move||for i in 0..100 {
+                assert!(loki_info!(sender, "{}-{}", t, i).is_some());
+            })
+        }).collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        let metric = container.get(&["1"]);
+        assert_eq!(metric.lock().unwrap().pending(), 400);
+        assert_eq!(metric.lock().unwrap().collect_pending(), 400);
+
+        for i in 0..1100 {
+            sender.push(Level::Info, format!("overflow {}", i));
+        }
+        let mut m = metric.lock().unwrap();
+        m.collect_pending();
+        assert_eq!(m.len(), 1000);
+        assert_eq!(m.dropped(Level::Info), 500);
+    }
+
+    #[test]
+    fn sender_reserved_test(){
+        let container = Log::get(LogMetricConfBuilder::new().add_labels(&["sender_reserved"]).set_default_capacity(4).reserve_capacity(Level::Error, 1).build());
+        let sender = container.sender(&["1"]);
+        for i in 0..10 {
+            sender.push(Level::Debug, format!("debug {}", i));
+        }
+        assert_eq!(sender.len(), 3);
+        assert!(sender.push(Level::Error, "error").is_some());
+        assert!(sender.push(Level::Error, "error 2").is_none());
+
+        let metric = container.get(&["1"]);
+        let mut m = metric.lock().unwrap();
+        m.collect_pending();
+        assert_eq!(m.len_level(Level::Error), 1);
+        assert_eq!(m.dropped(Level::Debug), 7);
+    }
+
+    #[test]
+    fn sender_config_test(){
+        let builder = LogMetricConfBuilder::new().add_labels(&["sender_config"]);
+        let container = Log::get(builder.clone().build());
+        let sender = container.sender(&["1"]);
+        assert!(sender.push(Level::Info, "before").is_some());
+
+        // A config set on the stream reaches senders handed out earlier.
+        let metric = container.get(&["1"]);
+        metric.lock().unwrap().collect_pending();
+        metric.lock().unwrap().set_config(Arc::new(builder.set_min_level(Level::Warn).set_capture_location(true).build()));
+        assert!(sender.push(Level::Info, "after").is_none());
+        assert!(loki_warn!(sender, "located").is_some());
+
+        let mut m = metric.lock().unwrap();
+        m.collect_pending();
+        assert_eq!(m.pop().unwrap().message, "before");
+        assert!(m.pop().unwrap().fields.iter().any(|(name, _)|*name == "line"));
+    }
+
+    #[test]
+    fn sender_capacity_test(){
+        let container = Log::get(LogMetricConfBuilder::new().add_labels(&["sender_capacity"]).set_default_capacity(1000).build());
+        let sender = container.sender(&["1"]);
+        let accepted = (0..1100).filter(|i|sender.push(Level::Info, format!("message{}", i)).is_some()).count();
+        assert_eq!(accepted, 1000);
+        assert_eq!(sender.len(), 1000);
+
+        let metric = container.get(&["1"]);
+        assert_eq!(metric.lock().unwrap().collect_pending(), 1000);
+        assert_eq!(metric.lock().unwrap().dropped(Level::Info), 100);
+    }
+
+    #[test]
+    fn sender_limits_test(){
+        let container = Log::get(LogMetricConfBuilder::new().add_labels(&["sender_limits"]).set_sample_every(2).set_rate_limit(0.001, 2).build());
+        let sender = container.sender(&["1"]);
+        let accepted = (0..4).filter(|i|sender.push_lazy(Level::Info, ||format!("message{}", i)).is_some()).count();
+        assert_eq!(accepted, 2);
+        // Sampled out and rate limited lines are never formatted.
+        assert!(sender.push_lazy(Level::Info, ||->String{ panic!("formatted a suppressed line") }).is_none());
+        assert!(sender.push_lazy(Level::Info, ||->String{ panic!("formatted a suppressed line") }).is_none());
+
+        let metric = container.get(&["1"]);
+        let mut m = metric.lock().unwrap();
+        assert_eq!(m.collect_pending(), 2);
+        // Locked pushes share the sampling counter and the token bucket.
+        assert!(m.push("locked".to_string()).is_none());
+        assert_eq!(m.suppressed(), Suppressed { rate_limited: 2, sampled: 3 });
+    }
+
+    #[test]
+    fn selector_cache_test(){
+        let container = Log::get(LogMetricConfBuilder::new().add_const_label("app", "selector").add_labels(&["selector_path", "selector_host"]).build());
+        let first = container.get(&["/a\"b", "web-1"]);
+        let second = container.get(&["/c", "web-1"]);
+        let (first, second) = (first.lock().unwrap(), second.lock().unwrap());
+        assert_eq!(first.selector().as_ref(), r#"{app="selector",selector_path="/a\"b",selector_host="web-1"}"#);
+        assert!(Arc::ptr_eq(&first.label_values()[1], &second.label_values()[1]));
+    }
+
+    #[test]
+    fn proto_encoder_test(){
+        use quick_protobuf::message::MessageWrite;
+        let entry = |secs:u64, line:&str, metadata:&[(&str, &str)]|LokiEntry{
+            ts: std::time::UNIX_EPOCH + Duration::from_millis(secs),
+            line: line.to_string(),
+            metadata: metadata.iter().map(|(k, v)|(k.to_string(), v.to_string())).collect(),
+        };
+        let streams = vec![
+            LokiStream{ labels: Vec::new(), selector: "{app=\"a\"}".into(), entries: vec![
+                entry(1_700_000_000_123, "first", &[]),
+                entry(1_700_000_001_000, "", &[("trace_id", "abc"), ("empty", "")]),
+            ]},
+            LokiStream{ labels: Vec::new(), selector: "{app=\"b\"}".into(), entries: vec![entry(0, &"x".repeat(300), &[])]},
+        ];
+
+        let mut expected = Vec::new();
+        logproto::PushRequest::from(streams.iter().map(logproto::Stream::from).collect::<Vec<_>>())
+            .write_message(&mut quick_protobuf::Writer::new(&mut expected))
+            .unwrap();
+        let mut encoded = Vec::new();
+        assert_eq!(ProtoEncoder::new().encode(&streams, &mut encoded), expected.len());
+        assert_eq!(encoded, expected);
+    }
+
+    #[test]
+    fn compression_test(){
+        use std::io::Read;
+        let metric = Log::get(LogMetricConfBuilder::new().add_labels(&["compression"]).build())
+            .get(&["1"]);
+        let push = |query:&str, configure:fn(LokiScrapeConfig)->LokiScrapeConfig|{
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}/loki/api/v1/push?{}", listener.local_addr().unwrap(), query);
+            let server = capture_request(listener);
+            metric.lock().unwrap().push("compressed ".repeat(20));
+            let config = configure(LokiScrapeConfig::new(&url, 1000).set_format(PushFormat::Json));
+            let report = config.get_scrape_process().send([metric.clone()].iter()).unwrap();
+            let (headers, body) = server.join().unwrap();
+            assert_eq!(body.len(), report.compressed_bytes);
+            assert_eq!((report.streams, report.entries, report.status), (1, 1, Some(204)));
+            assert_eq!(report.endpoint.as_deref(), url.split('?').next());
+            (headers.to_ascii_lowercase(), body, report)
+        };
+
+        let (headers, body, report) = push("", |c|c.set_compression(Compression::Gzip).set_compression_level(9));
+        assert!(headers.contains("content-encoding: gzip"));
+        assert!(report.compressed_bytes < report.raw_bytes);
+        let mut json = String::new();
+        flate2::read::GzDecoder::new(body.as_slice()).read_to_string(&mut json).unwrap();
+        assert_eq!(json.len(), report.raw_bytes);
+        assert!(json.contains("compressed compressed"));
+
+        let (headers, body, report) = push("compression=deflate&min_compress_size=100000", |c|c);
+        assert!(!headers.contains("content-encoding"));
+        assert_eq!(report.raw_bytes, report.compressed_bytes);
+        assert!(String::from_utf8(body).unwrap().contains("compressed compressed"));
+
+        // Pairs the format can't carry are refused, not swapped for another compression.
+        let gzip_protobuf = LokiScrapeConfig::new("http://localhost:3100/loki/api/v1/push", 1000).set_compression(Compression::Gzip);
+        assert!(matches!(gzip_protobuf.validate(), Err(Error::InvalidConfig(m)) if m.starts_with("compression:")));
+        metric.lock().unwrap().push("refused".to_string());
+        assert!(matches!(gzip_protobuf.get_scrape_process().send([metric.clone()].iter()), Err(Error::InvalidConfig(_))));
+        let snappy_json = LokiScrapeConfig::new("http://localhost:3100/loki/api/v1/push?format=json&compression=snappy", 1000);
+        assert!(snappy_json.validate().is_err());
+        let counts = Arc::new(CountingListener::new());
+        let scrape = Scrape::new();
+        assert_eq!(scrape.start_with_listener(snappy_json, counts.clone()), None);
+        assert_eq!(counts.errors(), 1);
+        assert_eq!(scrape.stop(), None);
+    }
+
+    #[test]
+    fn metrics_test(){
+        let registry = Registry::global();
+        let dropped = registry.entries_dropped(DropReason::Capacity);
+        let requests = registry.push_requests("204");
+        let metric = Log::get(LogMetricConfBuilder::new().add_labels(&["metrics"]).set_default_capacity(1).build())
+            .get(&["1"]);
+        metric.lock().unwrap().push("kept".to_string());
+        metric.lock().unwrap().push("dropped".to_string());
+        assert!(registry.entries_dropped(DropReason::Capacity) > dropped);
+
+        let text = registry.render();
+        assert!(text.contains("# TYPE log_loki_push_duration_seconds histogram"));
+        assert!(text.contains("log_loki_buffered_entries{container=\"metrics\"} 1\n"), "{}", text);
+        assert!(registry.streams().iter().any(|s|s.container == "metrics" && s.labels == vec![("metrics".to_string(), "1".to_string())] && s.dropped == 1));
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/loki/api/v1/push", listener.local_addr().unwrap());
+        let server = capture_request(listener);
+        LokiScrapeConfig::new(&url, 1000).get_scrape_process().send([metric.clone()].iter()).unwrap();
+        server.join().unwrap();
+        assert!(registry.push_requests("204") > requests);
+        assert!(registry.render().contains("log_loki_buffered_entries{container=\"metrics\"} 0\n"));
+    }
+
+    #[test]
+    fn status_server_test(){
+        let scrape = Scrape::new();
+        let metric = scrape.get(LogMetricConfBuilder::new().add_labels(&["status_server"]).build()).get(&["1"]);
+        metric.lock().unwrap().push("buffered".to_string());
+        let addr = scrape.start_status_server("127.0.0.1:0").unwrap();
+        let get = |path:&str|{
+            let resp = ureq::get(&format!("http://{}{}", addr, path)).call();
+            (resp.status(), resp.into_string().unwrap())
+        };
+
+        // An idle client that never sends its request doesn't hold up others.
+        let idle = std::net::TcpStream::connect(addr).unwrap();
+        let start = std::time::Instant::now();
+        let (status, body) = get("/metrics");
+        assert!(start.elapsed() < Duration::from_secs(1));
+        drop(idle);
+        assert_eq!(status, 200);
+        assert!(body.contains("log_loki_buffered_entries{container=\"status_server\"} 1"));
+
+        let (status, body) = get("/debug/streams");
+        assert_eq!(status, 200);
+        assert!(body.contains(r#"{"buffered":1,"container":"status_server","dropped":0,"labels":{"status_server":"1"}}"#), "{}", body);
+
+        assert_eq!(get("/healthz").0, 200);
+        scrape.health().record(false);
+        let (status, body) = get("/healthz");
+        assert_eq!(status, 503);
+        assert!(body.contains(r#""consecutive_failures":1"#));
+        assert_eq!(get("/nope").0, 404);
+
+        scrape.stop_status_server();
+        assert!(std::net::TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn retry_test(){
+        assert!(Error::Http { status: 503, body: String::new() }.is_retryable());
+        assert!(!Error::Http { status: 400, body: String::new() }.is_retryable());
+        assert_eq!(Error::RateLimited { retry_after: None }.status(), Some(429));
+        assert_eq!(Error::Timeout("read".to_string()).status(), None);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/loki/api/v1/push?max_retries=3&retry_backoff=10&flush_entries=1", listener.local_addr().unwrap());
+        let server = serve_responses(listener, vec![("503 Service Unavailable", ""), ("429 Too Many Requests", ""), ("204 No Content", "")]);
+        let counts = Arc::new(CountingListener::new());
+        let scrape = Scrape::new();
+        let metric = scrape.get(LogMetricConfBuilder::new().add_labels(&["retry"]).build()).get(&["1"]);
+        scrape.start_with_listener(LokiScrapeConfig::new(&url, 60000), counts.clone());
+        metric.lock().unwrap().push("message".to_string());
+
+        assert_eq!(server.join().unwrap().len(), 3);
+        scrape.stop();
+        assert_eq!(counts.retries(), 2);
+        assert_eq!(counts.throttled(), 1);
+        assert_eq!(counts.entries(), 1);
+        assert_eq!(counts.errors(), 0);
+    }
+
+    #[test]
+    fn retry_keeps_flush_test(){
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/loki/api/v1/push?format=json&max_retries=1&retry_backoff=500&flush_entries=1", listener.local_addr().unwrap());
+        let server = serve_responses(listener, vec![("503 Service Unavailable", ""), ("204 No Content", ""), ("204 No Content", "")]);
+        let scrape = Scrape::new();
+        let metric = scrape.get(LogMetricConfBuilder::new().add_labels(&["retry_flush"]).build()).get(&["1"]);
+        scrape.start(LokiScrapeConfig::new(&url, 60000));
+        metric.lock().unwrap().push("first".to_string());
+        std::thread::sleep(Duration::from_millis(200));
+        // Due while the first push waits to be retried.
+        metric.lock().unwrap().push("second".to_string());
+
+        // The flush is still pending after the retry, so the next scrape doesn't wait for the interval.
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !server.is_finished() && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(server.is_finished());
+        scrape.stop();
+        let bodies = server.join().unwrap();
+        assert!(String::from_utf8_lossy(&bodies[2]).contains("second"));
+    }
+
+    #[test]
+    fn rejection_test(){
+        let container = Log::get(LogMetricConfBuilder::new().add_labels(&["reject"]).build());
+        for i in 1..=2 {
+            container.get(&[&i.to_string()]).lock().unwrap().push(format!("message{}", i));
+        }
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/loki/api/v1/push?format=json", listener.local_addr().unwrap());
+        let server = serve_responses(listener, vec![
+            ("400 Bad Request", "entry with timestamp 2024-01-01 00:00:00 +0000 UTC ignored, reason: 'entry out of order' for stream: {reject=\"2\"},\ntotal ignored: 1 out of 2\n"),
+            ("204 No Content", ""),
+        ]);
+        let dropped = Registry::global().entries_dropped(DropReason::Rejected);
+        let metrics = container.values();
+        let report = LokiScrapeConfig::new(&url, 1000).get_scrape_process().send(metrics.iter()).unwrap();
+
+        let bodies = server.join().unwrap();
+        let resent = String::from_utf8(bodies[1].clone()).unwrap();
+        assert!(resent.contains("message1") && !resent.contains("message2"), "{}", resent);
+        assert_eq!(report.entries, 1);
+        assert_eq!(report.rejected, vec![Rejection { selector: "{reject=\"2\"}".to_string(), reason: "entry out of order".to_string(), entries: 1 }]);
+        assert_eq!(Registry::global().entries_dropped(DropReason::Rejected), dropped + 1);
+
+        let counted = Log::get(LogMetricConfBuilder::new().add_labels(&["reject_count"]).build());
+        for i in 1..=3 {
+            counted.get(&["1"]).lock().unwrap().push(format!("message{}", i));
+        }
+        counted.get(&["2"]).lock().unwrap().push("kept".to_string());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/loki/api/v1/push?format=json", listener.local_addr().unwrap());
+        // Loki lists one of the two ignored entries; the total covers both.
+        let server = serve_responses(listener, vec![
+            ("400 Bad Request", "entry with timestamp 2024-01-01 00:00:00 +0000 UTC ignored, reason: 'entry too far behind' for stream: {reject_count=\"1\"},\ntotal ignored: 2 out of 4\n"),
+            ("204 No Content", ""),
+        ]);
+        let metrics = counted.values();
+        let report = LokiScrapeConfig::new(&url, 1000).get_scrape_process().send(metrics.iter()).unwrap();
+
+        let bodies = server.join().unwrap();
+        let resent = String::from_utf8(bodies[1].clone()).unwrap();
+        assert!(resent.contains("kept") && !resent.contains("message"), "{}", resent);
+        assert_eq!(report.rejected, vec![Rejection { selector: "{reject_count=\"1\"}".to_string(), reason: "entry too far behind".to_string(), entries: 2 }]);
+        assert_eq!(Registry::global().entries_dropped(DropReason::Rejected), dropped + 3);
+    }
+
+    #[test]
+    fn rejection_resend_failed_test(){
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/loki/api/v1/push?format=json", listener.local_addr().unwrap());
+        let server = serve_responses(listener, vec![
+            ("400 Bad Request", "entry with timestamp 2024-01-01 00:00:00 +0000 UTC ignored, reason: 'entry out of order' for stream: {reject_resend=\"2\"},\ntotal ignored: 1 out of 3\n"),
+            ("500 Internal Server Error", ""),
+        ]);
+        let counts = Arc::new(CountingListener::new());
+        let scrape = Scrape::new();
+        let container = scrape.get(LogMetricConfBuilder::new().add_labels(&["reject_resend"]).build());
+        scrape.start_with_listener(LokiScrapeConfig::new(&url, 60000), counts.clone());
+        container.get(&["1"]).lock().unwrap().push("message1".to_string());
+        container.get(&["2"]).lock().unwrap().push("message2".to_string());
+        container.get(&["2"]).lock().unwrap().push("message3".to_string());
+        let pushed = Registry::global().entries_pushed();
+        scrape.stop();
+
+        server.join().unwrap();
+        assert_eq!(counts.errors(), 1);
+        assert_eq!(counts.dropped(DropReason::Rejected), 1);
+        // Loki kept the other entry of the rejected stream, even though the resend failed.
+        assert!(Registry::global().entries_pushed() > pushed);
+    }
+
+    #[test]
+    fn listener_test(){
+        let (tx, rx) = mpsc::channel();
+        let counts = Arc::new(CountingListener::new());
+        let listener:Box<dyn ScrapeEvents + Send> = Box::new(counts.clone());
+        let scrape = Scrape::new();
+        let metric = scrape.get(LogMetricConfBuilder::new().add_labels(&["listener"]).set_default_capacity(1).build()).get(&["1"]);
+        scrape.start_with_listener(ChannelScrapeConfig{ sender: Mutex::new(tx), flush_entries: 0 }, listener);
+        metric.lock().unwrap().push("kept".to_string());
+        metric.lock().unwrap().push("dropped".to_string());
+
+        // Nothing is due before the interval, so the entry goes out with the shutdown flush.
+        scrape.stop();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 1);
+        assert_eq!(counts.shutdown_flushes(), 1);
+        assert_eq!(counts.bytes(), 1);
+        assert_eq!(counts.dropped(DropReason::Capacity), 1);
+        assert_eq!(counts.errors(), 0);
+    }
+
+    #[test]
+    fn backlog_test(){
+        struct FailingProcess;
+        impl ScrapeProcess for FailingProcess {
+            fn send(&mut self, _items: std::slice::Iter<'_, Arc<Mutex<LogMetric>>>)->Result<ScrapeReport>{
+                Err(Error::Timeout("write".to_string()))
+            }
+        }
+        struct FailingConfig;
+        impl ScrapeConfig for FailingConfig {
+            type ScrapeType = FailingProcess;
+            fn get_scrape_interval(&self)->Duration{ Duration::from_secs(60) }
+            fn get_scrape_process(&self)->Self::ScrapeType{ FailingProcess }
+        }
+
+        let counts = Arc::new(CountingListener::new());
+        let scrape = Scrape::new();
+        let metric = scrape.get(LogMetricConfBuilder::new().add_labels(&["backlog"]).build()).get(&["1"]);
+        scrape.start_with_listener(FailingConfig, counts.clone());
+        metric.lock().unwrap().push("message1".to_string());
+        metric.lock().unwrap().push("message2".to_string());
+        scrape.stop();
+
+        assert_eq!(counts.errors(), 1);
+        assert_eq!(counts.backlog(), Backlog { entries: 2, bytes: 16 });
+    }
+
+    #[test]
+    fn dropped_labels_test(){
+        #[derive(Default)]
+        struct Drops(Mutex<Vec<(crate::pipeline::Labels, DropReason, u64)>>);
+        impl ScrapeEvents for Drops {
+            fn on_dropped(&self, labels:&[(String, String)], reason:DropReason, count:u64){
+                self.0.lock().unwrap().push((labels.to_vec(), reason, count));
+            }
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let drops = Arc::new(Drops::default());
+        let scrape = Scrape::new();
+        let metric = scrape.get(LogMetricConfBuilder::new().add_const_label("app", "api").add_labels(&["dropped_labels"]).set_default_capacity(1).build())
+            .get(&["1"]);
+        scrape.start_with_listener(ChannelScrapeConfig{ sender: Mutex::new(tx), flush_entries: 0 }, drops.clone());
+        metric.lock().unwrap().push("kept".to_string());
+        metric.lock().unwrap().push("dropped".to_string());
+        scrape.stop();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 1);
+
+        let labels = vec![("app".to_string(), "api".to_string()), ("dropped_labels".to_string(), "1".to_string())];
+        assert_eq!(*drops.0.lock().unwrap(), vec![(labels, DropReason::Capacity, 1)]);
+    }
+
+    #[test]
+    fn pipeline_dropped_test(){
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/loki/api/v1/push?format=json", listener.local_addr().unwrap());
+        let server = serve_responses(listener, vec![("204 No Content", "")]);
+        let counts = Arc::new(CountingListener::new());
+        let scrape = Scrape::new();
+        let pipeline = Pipeline::new().drop(regex::Regex::new("healthcheck").unwrap());
+        let metric = scrape.get(LogMetricConfBuilder::new().add_labels(&["pipeline_dropped"]).set_pipeline(pipeline).build())
+            .get(&["1"]);
+        scrape.start_with_listener(LokiScrapeConfig::new(&url, 60000), counts.clone());
+        metric.lock().unwrap().push("healthcheck".to_string());
+        metric.lock().unwrap().push("message".to_string());
+        scrape.stop();
+
+        let body = String::from_utf8(server.join().unwrap().remove(0)).unwrap();
+        assert!(body.contains("message") && !body.contains("healthcheck"), "{}", body);
+        assert_eq!(counts.entries(), 1);
+        assert_eq!(counts.dropped(DropReason::Pipeline), 1);
+    }
+
+    #[test]
+    fn structured_metadata_test(){
+        let metric = Log::get(LogMetricConfBuilder::new().add_labels(&["metadata"]).build())
+            .get(&["1"]);
+        {
+            let mut m = metric.lock().unwrap();
+            m.push_with_metadata(Level::Info, "traced", vec![("trace_id".to_string(), "abc".to_string())]);
+            m.push_entry(LogMessage::with_level(Level::Info, "plain"));
+        }
+        let mut streams = LokiStream::drain(&mut metric.lock().unwrap());
+        let json = LokiModel::from(Vec::new()).to_json();
+        assert_eq!(json.to_string(), r#"{"streams":[]}"#);
+
+        let values = streams[0].to_json()["values"].clone();
+        assert_eq!(values[0][2]["trace_id"], "abc");
+        assert_eq!(values[1].as_array().unwrap().len(), 2);
+
+        let stream = streams.remove(0);
+        let stream = logproto::Stream::from(&stream);
+        assert_eq!(stream.entries[0].structuredMetadata[0].name, "trace_id");
+        assert_eq!(stream.entries[0].structuredMetadata[0].value, "abc");
+        assert!(stream.entries[1].structuredMetadata.is_empty());
+    }
+
+    #[test]
+    fn trace_context_test(){
+        let provider = Arc::new(||Some(TraceContext{ trace_id: "4bf92f35".into(), span_id: "00f067aa".into() }));
+        let get = |name:&str, mode:ContextMode|{
+            Log::get(LogMetricConfBuilder::new().add_labels(&[name]).set_context_provider(provider.clone(), mode).build())
+                .get(&["1"])
+        };
+
+        let metadata = get("context_metadata", ContextMode::Metadata);
+        metadata.lock().unwrap().push("traced".to_string());
+        let entry = LokiStream::drain(&mut metadata.lock().unwrap()).remove(0).entries.remove(0);
+        assert_eq!(entry.line, "traced");
+        assert_eq!(entry.metadata, vec![("trace_id".to_string(), "4bf92f35".to_string()), ("span_id".to_string(), "00f067aa".to_string())]);
+
+        let fields = get("context_fields", ContextMode::Fields);
+        fields.lock().unwrap().push("traced".to_string());
+        let entry = LokiStream::drain(&mut fields.lock().unwrap()).remove(0).entries.remove(0);
+        assert_eq!(entry.line, "traced trace_id=4bf92f35 span_id=00f067aa");
+        assert!(entry.metadata.is_empty());
+    }
+
+    #[test]
+    fn sender_trace_context_test(){
+        thread_local!{
+            static SPAN: std::cell::RefCell<Option<TraceContext>> = const { std::cell::RefCell::new(None) };
+        }
+        let provider = Arc::new(||SPAN.with(|span|span.borrow().clone()));
+        let metric = Log::get(LogMetricConfBuilder::new().add_labels(&["sender_context"]).set_context_provider(provider, ContextMode::Metadata).build())
+            .get(&["1"]);
+        let sender = metric.lock().unwrap().sender();
+        std::thread::spawn(move||{
+            SPAN.with(|span|*span.borrow_mut() = Some(TraceContext{ trace_id: "4bf92f35".into(), span_id: "00f067aa".into() }));
+            sender.push(Level::Info, "traced");
+        }).join().unwrap();
+
+        // Drained here, outside the span, with the ids of the pushing thread.
+        let entry = LokiStream::drain(&mut metric.lock().unwrap()).remove(0).entries.remove(0);
+        assert_eq!(entry.metadata, vec![("trace_id".to_string(), "4bf92f35".to_string()), ("span_id".to_string(), "00f067aa".to_string())]);
+    }
+
+    #[test]
+    fn interner_sweep_test(){
+        let interner = crate::util::Interner::new();
+        let kept = interner.intern("kept");
+        for i in 0..10_000 {
+            interner.intern(&format!("request-{}", i));
+        }
+        assert!(interner.len() <= 2048, "{}", interner.len());
+        interner.sweep();
+        assert_eq!(interner.len(), 1);
+        assert!(Arc::ptr_eq(&kept, &interner.intern("kept")));
+    }
+
+    #[derive(log_loki_derive::LokiLabels)]
+    struct HttpLabels<'a> {
+        method: &'a str,
+        #[loki(rename = "status_code")]
+        status: u16,
+    }
+
+    #[test]
+    fn typed_labels_test(){
+        assert_eq!(HttpLabels::label_names(), &["method", "status_code"]);
+        let container = Log::get_typed::<HttpLabels>();
+        let metric = container.get(&HttpLabels{ status: 404, method: "GET" });
+        assert_eq!(metric.lock().unwrap().labels(), &vec!["GET".to_string(), "404".to_string()]);
+        assert_eq!(metric.lock().unwrap().selector().as_ref(), r#"{method="GET",status_code="404"}"#);
+        assert!(Arc::ptr_eq(&metric, &container.get(&HttpLabels{ method: "GET", status: 404 })));
+    }
+
+    #[test]
+    fn config_test(){
+        let vars = |pairs:&[(&str, &str)]|pairs.iter().map(|(k, v)|(k.to_string(), v.to_string())).collect::<Vec<_>>();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/loki/api/v1/push", listener.local_addr().unwrap());
+        let config = Config::from_vars(vars(&[
+            ("LOKI_URL", &url), ("LOKI_TENANT", "team-a"), ("LOKI_SCRAPE_INTERVAL", "250"), ("LOKI_FORMAT", "json"),
+            ("LOKI_LOG_MIN_LEVEL", "warn"), ("LOKI_LOG_DEFAULT_CAPACITY", "16"), ("LOKI_ADDR", "ignored"),
+        ])).unwrap();
+        assert_eq!(config.scrape().get_tenant(), Some("team-a"));
+        assert_eq!(config.scrape().get_scrape_interval(), Duration::from_millis(250));
+        let log = config.log().clone().add_labels(&["config"]).build();
+        assert_eq!((log.get_min_level(), log.get_default_capacity()), (Level::Warn, 16));
+
+        let metric = Log::get(log).get(&["1"]);
+        metric.lock().unwrap().push_with_level(Level::Error, "message".to_string());
+        let server = capture_request(listener);
+        config.scrape().get_scrape_process().send([metric].iter()).unwrap();
+        let (headers, _) = server.join().unwrap();
+        assert!(headers.to_ascii_lowercase().contains("x-scope-orgid: team-a"), "{}", headers);
+
+        let error = |pairs:&[(&str, &str)]|match Config::from_vars(vars(pairs)) {
+            Err(Error::InvalidConfig(message)) => message,
+            _ => panic!("expected invalid config for {:?}", pairs),
+        };
+        assert_eq!(error(&[]), "url: missing");
+        assert!(error(&[("LOKI_URL", "localhost:3100")]).starts_with("url:"));
+        assert!(error(&[("LOKI_URL", "http://loki"), ("LOKI_SCRAPE_INTERVAL", "soon")]).starts_with("scrape_interval:"));
+        assert!(error(&[("LOKI_URL", "http://loki"), ("LOKI_COMPRESSION", "gzip")]).starts_with("compression:"));
+        assert!(error(&[("LOKI_URL", "http://loki"), ("LOKI_LOG_MIN_LEVEL", "loud")]).starts_with("min_level:"));
+        assert_eq!(error(&[("LOKI_URL", "http://loki"), ("LOKI_LOG_MAX_LINE_SIZ", "100")]), "LOKI_LOG_MAX_LINE_SIZ: unknown variable");
+        assert_eq!(error(&[("LOKI_URL", "http://loki"), ("LOKI_RETRIES", "3")]), "LOKI_RETRIES: unknown variable");
+
+        #[cfg(feature = "toml")]
+        {
+            let config = Config::from_toml_str("[loki]\nurl = \"http://loki:3100/loki/api/v1/push\"\ncompression = \"gzip\"\nformat = \"json\"\n\n[log]\ncapture_location = true\n").unwrap();
+            assert!(config.log().clone().build().get_capture_location());
+            assert!(matches!(Config::from_toml_str("[loki]\nurl = \"http://loki\"\nretries = 3\n"), Err(Error::InvalidConfig(m)) if m == "loki.retries: unknown key"));
+            assert!(matches!(Config::from_toml_str("[loki]\nurl = \"http://loki\"\nmax_retries = -1\n"), Err(Error::InvalidConfig(_))));
+        }
+    }
+
+    #[test]
+    fn reload_test(){
+        let (tx1, rx1) = mpsc::channel();
+        let (tx2, rx2) = mpsc::channel();
+        let counts = Arc::new(CountingListener::new());
+        let scrape = Scrape::new();
+        let metric = scrape.get(LogMetricConfBuilder::new().add_labels(&["reload"]).build()).get(&["1"]);
+        assert!(scrape.reload(ChannelScrapeConfig{ sender: Mutex::new(tx2.clone()), flush_entries: 1 }).is_none());
+        scrape.start_with_listener(ChannelScrapeConfig{ sender: Mutex::new(tx1), flush_entries: 0 }, counts.clone());
+        assert!(scrape.reload(LokiScrapeConfig::new("http://localhost:3100/loki/api/v1/push", 1000)).is_none());
+
+        // Buffered before the swap, pushed through the new process.
+        metric.lock().unwrap().push("before".to_string());
+        scrape.reload(ChannelScrapeConfig{ sender: Mutex::new(tx2), flush_entries: 1 }).unwrap();
+        assert_eq!(rx2.recv_timeout(Duration::from_secs(5)).unwrap(), 1);
+        metric.lock().unwrap().push("after".to_string());
+        assert_eq!(rx2.recv_timeout(Duration::from_secs(5)).unwrap(), 1);
+        assert!(rx1.try_recv().is_err());
+        assert_eq!(counts.reloads(), 1);
+        scrape.stop();
+
+        #[cfg(feature = "toml")]
+        {
+            let path = std::env::temp_dir().join(format!("log_loki_reload_{}.toml", std::process::id()));
+            std::fs::write(&path, "[loki]\nurl = \"http://localhost:3100/loki/api/v1/push\"\n").unwrap();
+            let counts = Arc::new(CountingListener::new());
+            let scrape = Scrape::new();
+            scrape.start_with_listener(LokiScrapeConfig::new("http://localhost:3100/loki/api/v1/push", 60000), counts.clone());
+            scrape.watch_config(&path, Duration::from_millis(10)).unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            std::fs::write(&path, "[loki]\nurl = \"http://localhost:3100/loki/api/v1/push\"\nscrape_interval = 0\n").unwrap();
+            let start = Instant::now();
+            while counts.errors() == 0 && start.elapsed() < Duration::from_secs(5) {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            assert_eq!((counts.errors(), counts.reloads()), (1, 0));
+            scrape.stop();
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+
     #[test]
     fn it_works()
     {
         let metric1 = {
             Log::get(LogMetricConfBuilder::new().add_labels(&["one","two"]).set_default_capacity(2).build())
-                .lock()
-                .unwrap()
                 .get(&["1","2"])
         };
         {
@@ -65,8 +1188,6 @@ mod tests {
         }
         let metric2 = {
             Log::get(LogMetricConfBuilder::new().add_labels(&["three"]).build())
-                .lock()
-                .unwrap()
                 .get(&["3"])
         };
         {
@@ -77,8 +1198,6 @@ mod tests {
         }
         let metric3 = {
             Log::get(LogMetricConfBuilder::new().add_labels(&["three"]).build())
-                .lock()
-                .unwrap()
                 .get(&["4"])
         };
         {
@@ -89,8 +1208,6 @@ mod tests {
         }
         let metric3 = {
             Log::get(LogMetricConfBuilder::new().add_labels(&["three"]).build())
-                .lock()
-                .unwrap()
                 .get(&["4"])
         };
         {
@@ -99,6 +1216,6 @@ mod tests {
         }
 
 
-        let _data:LokiModel = Log::map(|e|LokiStream::from(e)).into();
+        let _data:LokiModel = Log::map(LokiStream::drain).into_iter().flatten().collect::<Vec<_>>().into();
     }
 }
@@ -0,0 +1,197 @@
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::log::Suppressed;
+use crate::loki::{render_selector, Rejection};
+use crate::metrics::DropReason;
+use crate::scrape::{Backlog, ScrapeEvents, ScrapeReport};
+
+fn selector(labels:&[(String, String)])->String{
+    render_selector(labels.iter().map(|(k, v)|(k.as_str(), v.as_str())))
+}
+
+/// Writes every scrape event as one line to stderr.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StderrListener;
+
+impl ScrapeEvents for StderrListener {
+    fn on_start(&self){
+        eprintln!("log_loki: scrape started");
+    }
+
+    fn on_scrape_report(&self, report:&ScrapeReport){
+        if report.entries > 0 {
+            eprintln!("log_loki: pushed {} entries in {} streams ({} bytes, {:?})",
+                report.entries, report.streams, report.compressed_bytes, report.latency);
+        }
+    }
+
+    fn on_suppressed(&self, labels:&[(String, String)], suppressed:Suppressed){
+        eprintln!("log_loki: suppressed {} lines for {} (rate limited: {}, sampled: {})",
+            suppressed.total(), selector(labels), suppressed.rate_limited, suppressed.sampled);
+    }
+
+    fn on_dropped(&self, labels:&[(String, String)], reason:DropReason, count:u64){
+        eprintln!("log_loki: dropped {} entries for {} ({})", count, selector(labels), reason.as_str());
+    }
+
+    fn on_rejected(&self, rejection:&Rejection){
+        eprintln!("log_loki: Loki rejected {} entries for {}: {}", rejection.entries, rejection.selector, rejection.reason);
+    }
+
+    fn on_retry(&self, attempt:u32, delay:Duration, err:&dyn Error){
+        eprintln!("log_loki: retry {} in {:?}: {}", attempt, delay, err);
+    }
+
+    fn on_throttled(&self, delay:Duration){
+        eprintln!("log_loki: throttled, waiting {:?}", delay);
+    }
+
+    fn on_error(&self, err:&dyn Error){
+        eprintln!("log_loki: push failed: {}", err);
+    }
+
+    fn on_shutdown_flush(&self, result:Result<&ScrapeReport, &dyn Error>){
+        match result {
+            Ok(report) => eprintln!("log_loki: flushed {} entries on shutdown", report.entries),
+            Err(err) => eprintln!("log_loki: shutdown flush failed: {}", err),
+        }
+    }
+
+    fn on_reload(&self, result:Result<(), &dyn Error>){
+        match result {
+            Ok(()) => eprintln!("log_loki: config reloaded"),
+            Err(err) => eprintln!("log_loki: config reload failed: {}", err),
+        }
+    }
+
+    fn on_backlog(&self, backlog:&Backlog){
+        if backlog.entries > 0 {
+            eprintln!("log_loki: {} entries ({} bytes) still buffered", backlog.entries, backlog.bytes);
+        }
+    }
+
+    fn on_end(&self){
+        eprintln!("log_loki: scrape stopped");
+    }
+}
+
+/// Counts scrape events into atomics. Share it through an `Arc` to read the
+/// counters while the scrape thread is running.
+#[derive(Debug, Default)]
+pub struct CountingListener {
+    scrapes: AtomicU64,
+    entries: AtomicU64,
+    bytes: AtomicU64,
+    errors: AtomicU64,
+    retries: AtomicU64,
+    throttled: AtomicU64,
+    dropped: [AtomicU64; DropReason::ALL.len()],
+    shutdown_flushes: AtomicU64,
+    reloads: AtomicU64,
+    backlog_entries: AtomicU64,
+    backlog_bytes: AtomicU64,
+}
+
+impl CountingListener {
+    pub fn new()->Self{
+        CountingListener::default()
+    }
+
+    pub fn scrapes(&self)->u64{
+        self.scrapes.load(Ordering::Relaxed)
+    }
+
+    pub fn entries(&self)->u64{
+        self.entries.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes(&self)->u64{
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn errors(&self)->u64{
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    pub fn retries(&self)->u64{
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    pub fn throttled(&self)->u64{
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped(&self, reason:DropReason)->u64{
+        self.dropped[reason as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn shutdown_flushes(&self)->u64{
+        self.shutdown_flushes.load(Ordering::Relaxed)
+    }
+
+    pub fn reloads(&self)->u64{
+        self.reloads.load(Ordering::Relaxed)
+    }
+
+    /// The most recent backlog reported by the scrape thread.
+    pub fn backlog(&self)->Backlog{
+        Backlog {
+            entries: self.backlog_entries.load(Ordering::Relaxed) as usize,
+            bytes: self.backlog_bytes.load(Ordering::Relaxed) as usize,
+        }
+    }
+
+    fn count_report(&self, report:&ScrapeReport){
+        self.entries.fetch_add(report.entries as u64, Ordering::Relaxed);
+        self.bytes.fetch_add(report.compressed_bytes as u64, Ordering::Relaxed);
+    }
+}
+
+impl ScrapeEvents for CountingListener {
+    fn on_scrape_report(&self, report:&ScrapeReport){
+        self.scrapes.fetch_add(1, Ordering::Relaxed);
+        self.count_report(report);
+    }
+
+    fn on_dropped(&self, _labels:&[(String, String)], reason:DropReason, count:u64){
+        self.dropped[reason as usize].fetch_add(count, Ordering::Relaxed);
+    }
+
+    fn on_rejected(&self, rejection:&Rejection){
+        self.dropped[DropReason::Rejected as usize].fetch_add(rejection.entries as u64, Ordering::Relaxed);
+    }
+
+    fn on_retry(&self, _attempt:u32, _delay:Duration, _err:&dyn Error){
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_throttled(&self, _delay:Duration){
+        self.throttled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_error(&self, _err:&dyn Error){
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_shutdown_flush(&self, result:Result<&ScrapeReport, &dyn Error>){
+        self.shutdown_flushes.fetch_add(1, Ordering::Relaxed);
+        match result {
+            Ok(report) => self.count_report(report),
+            Err(_) => { self.errors.fetch_add(1, Ordering::Relaxed); },
+        }
+    }
+
+    fn on_reload(&self, result:Result<(), &dyn Error>){
+        match result {
+            Ok(()) => { self.reloads.fetch_add(1, Ordering::Relaxed); },
+            Err(_) => { self.errors.fetch_add(1, Ordering::Relaxed); },
+        }
+    }
+
+    fn on_backlog(&self, backlog:&Backlog){
+        self.backlog_entries.store(backlog.entries as u64, Ordering::Relaxed);
+        self.backlog_bytes.store(backlog.bytes as u64, Ordering::Relaxed);
+    }
+}
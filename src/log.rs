@@ -1,29 +1,240 @@
-use std::sync::{Mutex,Arc};
+use std::sync::{Mutex,Arc,RwLock,OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::collections::{HashMap};
 use std::collections::vec_deque::{VecDeque};
 use std::vec::Vec;
 use std::iter::Iterator;
 use std::hash::Hasher;
+use std::time::Instant;
 use fnv::FnvHasher;
 
-use super::models::{LogMessage, LogMetricConf};
+use super::models::{LogMessage, LogMetricConf, Level, RateLimit, Sampling, LinePolicy, Metadata, Location};
+use super::flush::FlushTrigger;
+use super::util::{Interner, XorShift};
+use super::loki::render_selector;
+use super::format::{Field, Formatter};
+use super::context::attach_current;
+use super::labels::{LokiLabels, TypedContainer};
+use super::sender::{Inbox, LogSender};
+use super::metrics::{self, DropReason};
 use std::borrow::BorrowMut;
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Suppressed {
+    pub rate_limited: u64,
+    pub sampled: u64,
+}
+
+impl Suppressed {
+    pub fn total(&self)->u64{
+        self.rate_limited + self.sampled
+    }
+}
+
+const TRUNCATE_MARKER:&str = "...[truncated]";
+const UNBOUNDED_INBOX_CAPACITY:usize = 1024;
+const STREAM_SHARDS:usize = 16;
+
+lazy_static!{
+    static ref LABEL_VALUES: Interner = Interner::new();
+}
+
+fn floor_char_boundary(s:&str, index:usize)->usize{
+    if index >= s.len() {
+        return s.len();
+    }
+    let mut index = index;
+    while !s.is_char_boundary(index) {
+        index -= 1;
+    }
+    if index == 0 { s.chars().next().map_or(0, char::len_utf8) } else { index }
+}
+
+fn split_line(message:&LogMessage, max:usize)->Vec<LogMessage>{
+    let mut chunks = Vec::new();
+    let mut rest = message.message.as_str();
+    while !rest.is_empty() {
+        let (chunk, tail) = rest.split_at(floor_char_boundary(rest, max));
+        chunks.push(LogMessage { time: message.time, level: message.level, message: chunk.to_string(), fields: Vec::new(), metadata: message.metadata.clone() });
+        rest = tail;
+    }
+    chunks
+}
+
+fn truncate_line(line:&mut String, max:usize){
+    if max <= TRUNCATE_MARKER.len() {
+        line.truncate(floor_char_boundary(line, max));
+        return;
+    }
+    line.truncate(floor_char_boundary(line, max - TRUNCATE_MARKER.len()));
+    line.push_str(TRUNCATE_MARKER);
+}
+
+#[derive(Clone, Copy)]
+enum OpenMessage {
+    None,
+    Lane(usize, usize),
+    Rejected(DropReason),
+}
+
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit:RateLimit)->Self{
+        TokenBucket { limit, tokens: limit.burst as f64, updated: Instant::now() }
+    }
+
+    fn give_back(&mut self){
+        self.tokens = (self.tokens + 1.0).min(self.limit.burst as f64);
+    }
+
+    fn take(&mut self)->bool{
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.updated = now;
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst as f64);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return true;
+        }
+        false
+    }
+}
+
+// Sampling and rate limit of a stream. Shared with the stream's inbox so a
+// LogSender push is checked before its message is built, against the same
+// counters and token bucket as a locked push.
+pub(crate) struct Admission {
+    every_nth: AtomicU64,
+    ratio: AtomicU64,
+    seen: AtomicU64,
+    seed: AtomicU64,
+    limited: AtomicBool,
+    bucket: Mutex<Option<TokenBucket>>,
+}
+
+impl Admission {
+    fn new(config:&LogMetricConf, seed:u64)->Self{
+        let admission = Admission {
+            every_nth: AtomicU64::new(0),
+            ratio: AtomicU64::new(1f64.to_bits()),
+            seen: AtomicU64::new(0),
+            seed: AtomicU64::new(seed),
+            limited: AtomicBool::new(false),
+            bucket: Mutex::new(None),
+        };
+        admission.configure(config);
+        admission
+    }
+
+    fn configure(&self, config:&LogMetricConf){
+        let (every_nth, ratio) = match config.get_sampling() {
+            Sampling::All => (0, 1.0),
+            Sampling::EveryNth(n) => (n, 1.0),
+            Sampling::Ratio(ratio) => (0, ratio),
+        };
+        self.every_nth.store(every_nth, Ordering::Relaxed);
+        self.ratio.store(f64::to_bits(ratio), Ordering::Relaxed);
+        let bucket = config.get_rate_limit().map(TokenBucket::new);
+        self.limited.store(bucket.is_some(), Ordering::Relaxed);
+        *self.bucket.lock().unwrap() = bucket;
+    }
+
+    pub(crate) fn check(&self)->Result<(), DropReason>{
+        let n = self.every_nth.load(Ordering::Relaxed);
+        if n > 0 && (self.seen.fetch_add(1, Ordering::Relaxed) + 1) % n != 1 {
+            return Err(DropReason::Sampled);
+        }
+        let ratio = f64::from_bits(self.ratio.load(Ordering::Relaxed));
+        if ratio < 1.0 && self.next_f64() >= ratio {
+            return Err(DropReason::Sampled);
+        }
+        if self.limited.load(Ordering::Relaxed) && !self.bucket.lock().unwrap().as_mut().is_none_or(|b|b.take()) {
+            return Err(DropReason::RateLimited);
+        }
+        Ok(())
+    }
+
+    /// Returns the slot of an admitted message that was then dropped for
+    /// lack of room, so it doesn't count against the sampling or rate limit.
+    /// Ratio draws are independent and have nothing to give back.
+    pub(crate) fn refund(&self){
+        if self.every_nth.load(Ordering::Relaxed) > 0 {
+            self.seen.fetch_sub(1, Ordering::Relaxed);
+        }
+        if self.limited.load(Ordering::Relaxed) {
+            if let Some(bucket) = self.bucket.lock().unwrap().as_mut() {
+                bucket.give_back();
+            }
+        }
+    }
+
+    // Each draw seeds a fresh generator from an atomic Weyl sequence, so
+    // senders on several threads can sample without a lock.
+    fn next_f64(&self)->f64{
+        XorShift::with_seed(self.seed.fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed)).next_f64()
+    }
+}
+
+fn metric_selector(config:&LogMetricConf, values:&[Arc<str>])->Arc<str>{
+    let const_labels = config.get_const_labels().iter().map(|e|(e[0].as_str(), e[1].as_str()));
+    let labels = config.get_label_names().iter().map(|e|e.as_str()).zip(values.iter().map(|e|e.as_ref()));
+    render_selector(const_labels.chain(labels)).into()
+}
+
 pub struct LogMetric {
-    _labels: Vec<String>,
-    _messages: VecDeque<LogMessage>,
+    _labels: OnceLock<Vec<String>>,
+    _values: Vec<Arc<str>>,
+    _selector: Arc<str>,
+    _lanes: Vec<VecDeque<LogMessage>>,
+    _dropped: Vec<u64>,
     _config: Arc<LogMetricConf>,
     _capacity: usize,
+    _trigger: Option<Arc<FlushTrigger>>,
+    _admission: Arc<Admission>,
+    _suppressed: Suppressed,
+    _open: OpenMessage,
+    _oversized: u64,
+    _inbox: Option<Arc<Inbox>>,
+    _drops: [u64; DropReason::ALL.len()],
 }
 
 impl LogMetric {
     pub fn with_labels(config:Arc<LogMetricConf>, labels:&[&str])->Self{
         let default_capacity = config.get_default_capacity();
+        let key = LogContainer::get_key(labels);
+        let values:Vec<Arc<str>> = labels.iter().map(|s|LABEL_VALUES.intern(s)).collect();
         LogMetric {
-            _labels:labels.iter().map(|s| (*s).to_owned()).collect(),
-            _messages: match default_capacity { 0 => VecDeque::new(),v@_ => VecDeque::with_capacity(v)},
+            _selector: metric_selector(&config, &values),
+            _labels: OnceLock::new(),
+            _values: values,
+            _lanes: Level::ALL.iter().map(|_|VecDeque::new()).collect(),
+            _dropped: vec![0; Level::ALL.len()],
             _capacity: default_capacity,
+            _admission: Arc::new(Admission::new(&config, XorShift::from_time(key).next_u64())),
+            _suppressed: Suppressed::default(),
+            _open: OpenMessage::None,
+            _oversized: 0,
+            _inbox: None,
+            _drops: [0; DropReason::ALL.len()],
             _config: config,
+            _trigger: None,
+        }
+    }
+
+    pub(crate) fn set_flush_trigger(&mut self, trigger:Arc<FlushTrigger>){
+        let bytes = self.messages().map(|m|m.message.len()).sum();
+        let len = self.len();
+        trigger.add(len, bytes);
+        if let Some(inbox) = &self._inbox {
+            inbox.set_flush_trigger(trigger.clone());
+        }
+        if let Some(old) = self._trigger.replace(trigger){
+            old.sub(len, bytes);
         }
     }
 
@@ -35,75 +246,604 @@ impl LogMetric {
         &self._config
     }
 
+    pub fn set_config(&mut self, config:Arc<LogMetricConf>){
+        assert_eq!(self._config.get_label_names().len(), config.get_label_names().len());
+        self._selector = metric_selector(&config, &self._values);
+        self._admission.configure(&config);
+        if let Some(inbox) = &self._inbox {
+            inbox.set_config(config.clone());
+        }
+        self._config = config;
+    }
+
+    /// Label values as owned strings, built from the interned values on
+    /// first use.
     pub fn labels(&self)->&Vec<String>{
-        &self._labels
+        self._labels.get_or_init(||self._values.iter().map(|v|v.to_string()).collect())
+    }
+
+    /// Const labels followed by the stream's own labels, as name/value pairs.
+    pub fn label_pairs(&self)->Vec<(String, String)>{
+        let names = self._config.get_label_names();
+        let const_labels = self._config.get_const_labels();
+        let mut labels = Vec::with_capacity(const_labels.len() + names.len());
+        labels.extend(const_labels.iter().map(|e|(e[0].clone(), e[1].clone())));
+        labels.extend(names.iter().cloned().zip(self._values.iter().map(|v|v.to_string())));
+        labels
+    }
+
+    /// Label values interned across streams, in the order of `labels()`.
+    pub fn label_values(&self)->&[Arc<str>]{
+        &self._values
+    }
+
+    pub fn selector(&self)->&Arc<str>{
+        &self._selector
     }
 
     pub fn len(&self)->usize{
-        self._messages.len()
+        self._lanes.iter().map(|lane|lane.len()).sum()
+    }
+
+    pub fn is_empty(&self)->bool{
+        self._lanes.iter().all(|lane|lane.is_empty())
+    }
+
+    pub fn len_level(&self, level:Level)->usize{
+        self._lanes[level.index()].len()
+    }
+
+    pub fn dropped(&self, level:Level)->u64{
+        self._dropped[level.index()]
+    }
+
+    pub fn dropped_total(&self)->u64{
+        self._dropped.iter().sum()
+    }
+
+    pub fn oversized(&self)->u64{
+        self._oversized
+    }
+
+    pub fn suppressed(&self)->Suppressed{
+        self._suppressed
+    }
+
+    pub fn flush_suppressed(&mut self)->Option<Suppressed>{
+        let suppressed = std::mem::take(&mut self._suppressed);
+        if suppressed.total() == 0 {
+            return None;
+        }
+        self.push_message(LogMessage::with_level(Level::Warn, format!(
+            "log_loki suppressed {} lines (rate limited: {}, sampled: {})",
+            suppressed.total(), suppressed.rate_limited, suppressed.sampled)));
+        Some(suppressed)
+    }
+
+    pub fn sender(&mut self)->LogSender{
+        let capacity = match self._config.get_default_capacity() {
+            0 => UNBOUNDED_INBOX_CAPACITY,
+            capacity => capacity
+        };
+        let trigger = self._trigger.clone();
+        let config = self._config.clone();
+        let admission = self._admission.clone();
+        let inbox = self._inbox.get_or_insert_with(||{
+            let inbox = Inbox::new(capacity, config, admission);
+            if let Some(trigger) = trigger {
+                inbox.set_flush_trigger(trigger);
+            }
+            Arc::new(inbox)
+        });
+        LogSender::new(inbox.clone())
+    }
+
+    pub fn pending(&self)->usize{
+        self._inbox.as_ref().map_or(0, |inbox|inbox.len())
+    }
+
+    pub fn collect_pending(&mut self)->usize{
+        let inbox = match &self._inbox {
+            None => return 0,
+            Some(inbox) => inbox.clone()
+        };
+        let mut collected = 0;
+        for _ in 0..inbox.len() {
+            match inbox.pop() {
+                None => break,
+                Some(message) => if self.push_admitted(message).is_some() {
+                    collected += 1;
+                }
+            }
+        }
+        for level in Level::ALL.iter() {
+            let dropped = inbox.take_dropped(*level);
+            self._dropped[level.index()] += dropped;
+            self._drops[DropReason::InboxFull as usize] += dropped;
+        }
+        let suppressed = inbox.take_suppressed();
+        self._suppressed.sampled += suppressed.sampled;
+        self._suppressed.rate_limited += suppressed.rate_limited;
+        self._drops[DropReason::Sampled as usize] += suppressed.sampled;
+        self._drops[DropReason::RateLimited as usize] += suppressed.rate_limited;
+        collected
+    }
+
+    /// Drops counted since the last call, indexed like `DropReason::ALL`.
+    pub fn take_drops(&mut self)->[u64; DropReason::ALL.len()]{
+        std::mem::take(&mut self._drops)
+    }
+
+    pub(crate) fn record_drop(&mut self, reason:DropReason){
+        self._drops[reason as usize] += 1;
+        metrics::add_dropped(reason, 1);
     }
 
     pub fn push(&mut self, message:String)->Option<()>{
-        self.can_push()?;
-        self._messages.push_back(message.into());
-        Some(())
+        self.push_with(Level::default(), ||message.into())
+    }
+
+    pub fn push_with_level(&mut self, level:Level, message:String)->Option<()>{
+        self.push_with(level, ||LogMessage::with_level(level, message))
     }
 
     pub fn push_lazy<F,Ft>(&mut self, get_msg:F)->Option<()>
         where Ft:Into<LogMessage>, F:FnOnce()->Ft
     {
-        self.can_push()?;
-        self._messages.push_back(get_msg().into());
-        Some(())
+        self.push_with(Level::default(), ||get_msg().into())
+    }
+
+    pub fn push_lazy_with_level<F,Ft>(&mut self, level:Level, get_msg:F)->Option<()>
+        where Ft:Into<String>, F:FnOnce()->Ft
+    {
+        self.push_with(level, ||LogMessage::with_level(level, get_msg()))
+    }
+
+    pub fn push_fields<T:Into<String>>(&mut self, level:Level, message:T, fields:Vec<Field>)->Option<()>{
+        self.push_with(level, ||LogMessage::with_fields(level, message, fields))
+    }
+
+    pub fn push_fields_lazy<F,Ft>(&mut self, level:Level, get_msg:F)->Option<()>
+        where Ft:Into<String>, F:FnOnce()->(Ft, Vec<Field>)
+    {
+        self.push_with(level, ||{
+            let (message, fields) = get_msg();
+            LogMessage::with_fields(level, message, fields)
+        })
+    }
+
+    pub fn push_at<F,Ft>(&mut self, level:Level, location:Location, get_msg:F)->Option<()>
+        where Ft:Into<String>, F:FnOnce()->Ft
+    {
+        let capture = self._config.get_capture_location();
+        self.push_with(level, ||{
+            let fields = if capture {
+                vec![("file", location.file.into()), ("line", location.line.into()), ("module", location.module.into())]
+            } else {
+                Vec::new()
+            };
+            LogMessage::with_fields(level, get_msg(), fields)
+        })
+    }
+
+    pub fn push_with_metadata<T:Into<String>>(&mut self, level:Level, message:T, metadata:Metadata)->Option<()>{
+        self.push_with(level, ||{
+            let mut message = LogMessage::with_level(level, message);
+            message.metadata = metadata;
+            message
+        })
+    }
+
+    pub fn push_entry(&mut self, message:LogMessage)->Option<()>{
+        let level = message.level;
+        self.push_with(level, ||message)
     }
 
     pub fn pop(&mut self)->Option<LogMessage>{
-        self._messages.pop_front()
+        let mut oldest:Option<usize> = None;
+        for (i, lane) in self._lanes.iter().enumerate().rev() {
+            if let Some(m) = lane.front() {
+                if self.is_held(i) {
+                    continue;
+                }
+                if oldest.is_none_or(|o|m.time < self._lanes[o].front().unwrap().time) {
+                    oldest = Some(i);
+                }
+            }
+        }
+        self.pop_lane(oldest?)
+    }
+
+    /// Ends the open multiline message so it can be popped before its
+    /// max_wait has passed, e.g. on a final flush. Later continuation lines
+    /// start a new message.
+    pub fn close_multiline(&mut self){
+        if let OpenMessage::Lane(..) = self._open {
+            self._open = OpenMessage::None;
+        }
+    }
+
+    pub fn pop_level(&mut self, level:Level)->Option<LogMessage>{
+        self.pop_lane(level.index())
     }
 
     pub fn can_push(&self)->Option<()>{
-        if self._capacity > 0 && self._messages.len() == self._capacity{
+        self.can_push_level(Level::default())
+    }
+
+    pub fn can_push_level(&self, level:Level)->Option<()>{
+        if level < self._config.get_min_level() {
             return None;
         }
+        if self._capacity == 0 {
+            return Some(());
+        }
+        let index = level.index();
+        let reserved = self._config.get_reserved_capacity(level);
+        if self._lanes[index].len() < reserved {
+            return Some(());
+        }
+        let shared = self._capacity.saturating_sub(self._config.get_reserved_total());
+        let shared_used:usize = Level::ALL.iter()
+            .map(|l|self._lanes[l.index()].len().saturating_sub(self._config.get_reserved_capacity(*l)))
+            .sum();
+        if shared_used < shared {
+            return Some(());
+        }
+        None
+    }
+
+    fn push_with<F>(&mut self, level:Level, make:F)->Option<()>
+        where F:FnOnce()->LogMessage
+    {
+        if level < self._config.get_min_level() {
+            return None;
+        }
+        if self._config.get_multiline().is_some() {
+            // Sampling and rate limits apply per line, before formatting. A
+            // suppressed line ends the open message and takes its
+            // continuations with it.
+            if let Err(reason) = self.admit() {
+                self._open = OpenMessage::Rejected(reason);
+                return None;
+            }
+            let mut message = make();
+            attach_current(&self._config, &mut message);
+            return self.push_multiline(message);
+        }
+        self.check_push(level)?;
+        let mut message = make();
+        attach_current(&self._config, &mut message);
+        if message.level != level && self.can_push_level(message.level).is_none() {
+            self._admission.refund();
+            self._dropped[message.level.index()] += 1;
+            self.record_drop(DropReason::Capacity);
+            return None;
+        }
+        self.push_limited(message)
+    }
+
+    fn push_limited(&mut self, mut message:LogMessage)->Option<()>{
+        // Lines that are rendered at drain time are checked in limit_lines
+        // once their final length is known.
+        let rendered = message.fields.is_empty() && *self._config.get_formatter() == Formatter::Plain;
+        let (max, policy) = match self._config.get_max_line_size() {
+            Some((max, policy)) if rendered && message.message.len() > max => (max, policy),
+            _ => {
+                self.push_message(message);
+                return Some(());
+            }
+        };
+        self._oversized += 1;
+        match policy {
+            LinePolicy::Drop => {
+                self.record_drop(DropReason::Oversized);
+                return None;
+            },
+            LinePolicy::Truncate => {
+                truncate_line(&mut message.message, max);
+                self.push_message(message);
+            },
+            LinePolicy::Split => {
+                for (i, chunk) in split_line(&message, max).into_iter().enumerate() {
+                    if i > 0 && self.can_push_level(message.level).is_none() {
+                        self._dropped[message.level.index()] += 1;
+                        self.record_drop(DropReason::Capacity);
+                        break;
+                    }
+                    self.push_message(chunk);
+                }
+            }
+        }
         Some(())
     }
+
+    /// Applies max_line_size to drained lines after formatting, redaction
+    /// and the pipeline, which can all make a line longer than it was when
+    /// it was pushed.
+    pub(crate) fn limit_lines(&mut self, messages:Vec<LogMessage>)->Vec<LogMessage>{
+        let (max, policy) = match self._config.get_max_line_size() {
+            Some((max, policy)) if messages.iter().any(|m|m.message.len() > max) => (max, policy),
+            _ => return messages,
+        };
+        let mut limited = Vec::with_capacity(messages.len());
+        for mut message in messages {
+            if message.message.len() <= max {
+                limited.push(message);
+                continue;
+            }
+            self._oversized += 1;
+            match policy {
+                LinePolicy::Drop => self.record_drop(DropReason::Oversized),
+                LinePolicy::Truncate => {
+                    truncate_line(&mut message.message, max);
+                    limited.push(message);
+                },
+                LinePolicy::Split => limited.extend(split_line(&message, max)),
+            }
+        }
+        limited
+    }
+
+    fn push_multiline(&mut self, message:LogMessage)->Option<()>{
+        let config = self._config.clone();
+        let multiline = config.get_multiline().unwrap();
+        if multiline.is_continuation(&message.message) {
+            match self._open {
+                OpenMessage::Rejected(reason) => {
+                    if reason == DropReason::Capacity {
+                        self._admission.refund();
+                    }
+                    match reason {
+                        DropReason::Sampled => self._suppressed.sampled += 1,
+                        DropReason::RateLimited => self._suppressed.rate_limited += 1,
+                        _ => self._dropped[message.level.index()] += 1,
+                    }
+                    self.record_drop(reason);
+                    return None;
+                },
+                OpenMessage::Lane(index, lines) if lines < multiline.get_max_lines() => {
+                    let max_line = config.get_max_line_size().map_or(usize::MAX, |(max, _)|max);
+                    let open = self._lanes[index].back_mut().unwrap();
+                    let age = message.time.duration_since(open.time).unwrap_or_default();
+                    if age < multiline.get_max_wait() && open.message.len() + message.message.len() < max_line {
+                        open.message.push('\n');
+                        open.message.push_str(&message.message);
+                        if let Some(trigger) = &self._trigger {
+                            trigger.add(0, message.message.len() + 1);
+                        }
+                        self._open = OpenMessage::Lane(index, lines + 1);
+                        return Some(());
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        let index = message.level.index();
+        if self.check_capacity(message.level).is_none() {
+            self._admission.refund();
+            self._open = OpenMessage::Rejected(DropReason::Capacity);
+            return None;
+        }
+        if self.push_limited(message).is_none() {
+            self._open = OpenMessage::Rejected(DropReason::Capacity);
+            return None;
+        }
+        self._open = OpenMessage::Lane(index, 1);
+        Some(())
+    }
+
+    fn is_held(&self, index:usize)->bool{
+        match (self._open, self._config.get_multiline()) {
+            (OpenMessage::Lane(open, _), Some(multiline)) if open == index && self._lanes[index].len() == 1 => {
+                let first = self._lanes[index].front().unwrap();
+                first.time.elapsed().unwrap_or_default() < multiline.get_max_wait()
+            },
+            _ => false
+        }
+    }
+
+    // Capacity goes first so a message with no room doesn't use up a
+    // sampling or rate limit slot.
+    fn check_push(&mut self, level:Level)->Option<()>{
+        self.check_capacity(level)?;
+        self.admit().ok()
+    }
+
+    fn admit(&mut self)->Result<(), DropReason>{
+        let res = self._admission.check();
+        match res {
+            Err(DropReason::Sampled) => self._suppressed.sampled += 1,
+            Err(_) => self._suppressed.rate_limited += 1,
+            Ok(()) => return res,
+        }
+        self.record_drop(res.unwrap_err());
+        res
+    }
+
+    // Messages collected from the inbox were already sampled, rate limited
+    // and given their trace context by the LogSender that pushed them.
+    fn push_admitted(&mut self, message:LogMessage)->Option<()>{
+        if self._config.get_multiline().is_some() {
+            return self.push_multiline(message);
+        }
+        if self.check_capacity(message.level).is_none() {
+            self._admission.refund();
+            return None;
+        }
+        self.push_limited(message)
+    }
+
+    fn check_capacity(&mut self, level:Level)->Option<()>{
+        let res = self.can_push_level(level);
+        if res.is_none() {
+            self._dropped[level.index()] += 1;
+            self.record_drop(DropReason::Capacity);
+        }
+        res
+    }
+
+    fn messages(&self)->impl Iterator<Item=&LogMessage>{
+        self._lanes.iter().flat_map(|lane|lane.iter())
+    }
+
+    fn pop_lane(&mut self, index:usize)->Option<LogMessage>{
+        if self.is_held(index) {
+            return None;
+        }
+        let message = self._lanes[index].pop_front()?;
+        if let OpenMessage::Lane(open, _) = self._open {
+            if open == index && self._lanes[index].is_empty() {
+                self._open = OpenMessage::None;
+            }
+        }
+        if let Some(trigger) = &self._trigger {
+            trigger.sub(1, message.message.len());
+        }
+        Some(message)
+    }
+
+    fn push_message(&mut self, message:LogMessage){
+        self._open = OpenMessage::None;
+        if let Some(trigger) = &self._trigger {
+            trigger.add(1, message.message.len());
+            if self._config.get_flush_level().is_some_and(|level|message.level >= level) {
+                trigger.notify();
+            }
+        }
+        self._lanes[message.level.index()].push_back(message);
+    }
 }
 
-pub struct LogContainer {
+struct Stream {
+    metric: Arc<Mutex<LogMetric>>,
+    sender: OnceLock<LogSender>,
+}
+
+pub struct StreamMap {
     _config: Arc<LogMetricConf>,
-    _metrics: HashMap<u64, Arc<Mutex<LogMetric>>>
+    _shards: Vec<RwLock<HashMap<u64, Stream>>>,
+    _trigger: RwLock<Option<Arc<FlushTrigger>>>,
 }
 
-impl LogContainer {
-    pub fn with_config(config: LogMetricConf)->Self{
-        LogContainer {
+impl StreamMap {
+    fn with_config(config:LogMetricConf)->Self{
+        StreamMap {
             _config: Arc::new(config),
-            _metrics: HashMap::new(),
+            _shards: (0..STREAM_SHARDS).map(|_|RwLock::new(HashMap::new())).collect(),
+            _trigger: RwLock::new(None),
         }
     }
 
-    pub fn get(&mut self, labels:&[& str])->Arc<Mutex<LogMetric>>{
+    pub fn config(&self)->&Arc<LogMetricConf>{
+        &self._config
+    }
+
+    pub fn get(&self, labels:&[&str])->Arc<Mutex<LogMetric>>{
+        self.with_stream(labels, |stream|stream.metric.clone())
+    }
+
+    pub fn sender(&self, labels:&[&str])->LogSender{
+        self.with_stream(labels, |stream|stream.sender.get_or_init(||stream.metric.lock().unwrap().sender()).clone())
+    }
+
+    pub fn len(&self)->usize{
+        self._shards.iter().map(|shard|shard.read().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self)->bool{
+        self.len() == 0
+    }
+
+    pub fn values(&self)->Vec<Arc<Mutex<LogMetric>>>{
+        self._shards.iter()
+            .flat_map(|shard|shard.read().unwrap().values().map(|stream|stream.metric.clone()).collect::<Vec<_>>())
+            .collect()
+    }
+
+    fn with_stream<F, R>(&self, labels:&[&str], map:F)->R
+        where F:Fn(&Stream)->R
+    {
         assert_eq!(self._config.get_label_names().len(), labels.len());
 
         let key = LogContainer::get_key(labels);
-        let conf= self._config.clone();
-        self._metrics
-            .entry(key)
-            .or_insert_with(||Arc::new(Mutex::new(LogMetric::with_labels(conf, labels))))
-            .clone()
+        let shard = &self._shards[key as usize % STREAM_SHARDS];
+        if let Some(stream) = shard.read().unwrap().get(&key) {
+            return map(stream);
+        }
+        let mut shard = shard.write().unwrap();
+        let stream = shard.entry(key).or_insert_with(||{
+            let mut metric = LogMetric::with_labels(self._config.clone(), labels);
+            if let Some(trigger) = self._trigger.read().unwrap().clone() {
+                metric.set_flush_trigger(trigger);
+            }
+            Stream { metric: Arc::new(Mutex::new(metric)), sender: OnceLock::new() }
+        });
+        map(stream)
+    }
+
+    fn set_flush_trigger(&self, trigger:Arc<FlushTrigger>){
+        *self._trigger.write().unwrap() = Some(trigger.clone());
+        for shard in self._shards.iter() {
+            for stream in shard.read().unwrap().values() {
+                stream.metric.lock().unwrap().set_flush_trigger(trigger.clone());
+            }
+        }
+    }
+}
+
+/// Handed out as `Arc<LogContainer>`: stream lookups go through the sharded
+/// map, so no lock is held across the whole container.
+pub struct LogContainer {
+    _streams: Arc<StreamMap>,
+}
+
+impl LogContainer {
+    pub fn with_config(config: LogMetricConf)->Self{
+        LogContainer {
+            _streams: Arc::new(StreamMap::with_config(config)),
+        }
+    }
+
+    pub fn config(&self)->&Arc<LogMetricConf>{
+        self._streams.config()
+    }
+
+    pub fn name(&self)->String{
+        let config = self.config();
+        let mut name:Vec<String> = config.get_const_labels().iter().map(|e|format!("{}={}", e[0], e[1])).collect();
+        name.extend(config.get_label_names().iter().cloned());
+        name.join(",")
+    }
+
+    pub fn streams(&self)->&Arc<StreamMap>{
+        &self._streams
+    }
+
+    pub fn get(&self, labels:&[& str])->Arc<Mutex<LogMetric>>{
+        self._streams.get(labels)
+    }
+
+    pub fn sender(&self, labels:&[& str])->LogSender{
+        self._streams.sender(labels)
+    }
+
+    pub(crate) fn set_flush_trigger(&self, trigger:Arc<FlushTrigger>){
+        self._streams.set_flush_trigger(trigger);
     }
 
     pub fn map<F, R>(&self, mut map:F)->Vec<R>
         where F:FnMut(&mut LogMetric)->R {
-        self._metrics.iter().map(|(_,e)|map(e.lock().unwrap().borrow_mut())).collect()
+        self._streams.values().iter().map(|e|map(e.lock().unwrap().borrow_mut())).collect()
     }
 
-    pub fn values(&self)->std::collections::hash_map::Values<'_, u64, Arc<Mutex<LogMetric>>>{
-        self._metrics.values()
+    pub fn values(&self)->Vec<Arc<Mutex<LogMetric>>>{
+        self._streams.values()
     }
 
     pub fn set_capacity_for_all(&self, capacity:usize){
-        for (_,v) in self._metrics.iter(){
+        for v in self._streams.values(){
             v.lock().unwrap().set_capacity(capacity);
         }
     }
@@ -121,32 +861,40 @@ impl LogContainer {
 
 pub struct Log;
 lazy_static!{
-    static ref CONTAINERS: Mutex<HashMap<u64, Arc<Mutex<LogContainer>>>> = Mutex::new(HashMap::new());
+    static ref CONTAINERS: Mutex<HashMap<u64, Arc<LogContainer>>> = Mutex::new(HashMap::new());
 }
 
 #[allow(dead_code)]
 impl Log {
-    pub fn create(config:LogMetricConf)->Option<Arc<Mutex<LogContainer>>>{
+    pub fn create(config:LogMetricConf)->Option<Arc<LogContainer>>{
         use std::collections::hash_map::Entry::*;
         match CONTAINERS.lock().unwrap().entry(config.get_key()) {
-            Vacant(v)=>Some(v.insert(Arc::new(Mutex::new(LogContainer::with_config(config)))).clone()),
+            Vacant(v)=>Some(v.insert(Arc::new(LogContainer::with_config(config))).clone()),
             Occupied(_)=>None
         }
     }
 
-    pub fn get(config:LogMetricConf)->Arc<Mutex<LogContainer>>{
+    pub fn get(config:LogMetricConf)->Arc<LogContainer>{
         CONTAINERS.lock().unwrap()
             .entry(config.get_key())
-            .or_insert_with(||Arc::new(Mutex::new(LogContainer::with_config(config))))
+            .or_insert_with(||Arc::new(LogContainer::with_config(config)))
             .clone()
     }
 
+    pub fn containers()->Vec<Arc<LogContainer>>{
+        CONTAINERS.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn get_typed<T:LokiLabels>()->TypedContainer<T>{
+        TypedContainer::new(Log::get(T::conf()))
+    }
+
     pub fn map<F, R>(mut map:F)->Vec<R>
         where F:FnMut(&mut LogMetric)->R
     {
         CONTAINERS.lock().unwrap()
-            .iter()
-            .flat_map(|(_, container)| container.lock().unwrap().map(|e| map(e)))
+            .values()
+            .flat_map(|container| container.map(&mut map))
             .collect()
     }
 }
@@ -1,14 +1,26 @@
-use chrono::{DateTime, Utc};
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde_json::{json, Map, Value};
 
-use crate::models::LogMessage;
+use crate::models::{LogMessage, Metadata};
 use crate::log::LogMetric;
+use crate::dedup;
+use crate::redact::Redactor;
+use crate::pipeline::Labels;
+use crate::metrics::DropReason;
 
+mod encode;
+mod reject;
 mod scrape;
 
-pub use scrape::LokiScrapeConfig;
+pub use encode::ProtoEncoder;
+pub use reject::Rejection;
+pub use scrape::{LokiScrapeConfig, PushFormat, Compression};
 
-const FORMAT: &'static str = "%Y-%m-%dT%H:%M:%S%.6f%:z";
+pub(crate) const FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.6f%:z";
 
+#[allow(dead_code)]
 #[derive(Debug)]
 pub struct LokiModel {
     pub streams: Vec<LokiStream>
@@ -20,9 +32,17 @@ impl From<Vec<LokiStream>> for LokiModel{
     }
 }
 
+impl LokiModel {
+    pub fn to_json(&self)->Value{
+        json!({ "streams": self.streams.iter().map(LokiStream::to_json).collect::<Vec<_>>() })
+    }
+}
+
+#[allow(dead_code)]
 #[derive(Debug)]
 pub struct LokiStream {
-    pub labels: String,
+    pub labels: Labels,
+    pub selector: Arc<str>,
     pub entries: Vec<LokiEntry>
 }
 
@@ -31,47 +51,63 @@ impl LokiStream {
     pub fn len(&self)->usize{
         self.entries.len()
     }
-}
 
-impl From<&mut LogMetric> for LokiStream {
-    fn from(metric:&mut LogMetric)->Self{
-        let labels = get_labels_string(metric.config(),metric.labels());
-        let mut entries:Vec<LokiEntry> = Vec::with_capacity(metric.len());
+    pub fn is_empty(&self)->bool{
+        self.entries.is_empty()
+    }
 
-        while let Some(v) = metric.pop(){
-            entries.push(v.into());
-        }
+    pub fn selector(&self)->&str{
+        &self.selector
+    }
 
-        LokiStream{
-            labels,
-            entries
-        }
+    pub fn drain(metric:&mut LogMetric)->Vec<LokiStream>{
+        let messages = drain_messages(metric);
+        streams_from_messages(metric, messages, None)
+    }
+
+    pub fn to_json(&self)->Value{
+        let stream:Map<String, Value> = self.labels.iter().map(|(k, v)|(k.clone(), Value::String(v.clone()))).collect();
+        json!({ "stream": stream, "values": self.entries.iter().map(LokiEntry::to_json).collect::<Vec<_>>() })
     }
 }
 
+#[allow(dead_code)]
 #[derive(Debug)]
 pub struct LokiEntry {
-    pub ts: String,
-    pub line: String
+    pub ts: SystemTime,
+    pub line: String,
+    pub metadata: Metadata,
 }
 
 impl From<LogMessage> for LokiEntry {
     fn from(message:LogMessage)->Self {
         LokiEntry {
-            ts: format!("{}", DateTime::<Utc>::from(message.time).format(FORMAT)),
+            ts: message.time,
             line: message.message,
+            metadata: message.metadata,
+        }
+    }
+}
+
+impl LokiEntry {
+    fn to_json(&self)->Value{
+        let ts = self.ts.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string();
+        if self.metadata.is_empty() {
+            return json!([ts, self.line]);
         }
+        let metadata:Map<String, Value> = self.metadata.iter().map(|(k, v)|(k.clone(), Value::String(v.clone()))).collect();
+        json!([ts, self.line, metadata])
     }
 }
 
 mod protos {
     #![allow(non_snake_case, non_camel_case_types, non_upper_case_globals)]
     #![allow(unused_imports)]
+    #![allow(clippy::all)]
 
     include!(concat!(env!("OUT_DIR"), "/mod.rs"));
 }
 pub use protos::logproto;
-use crate::models::LogMetricConf;
 
 impl<'a> From<Vec<logproto::Stream<'a>>> for logproto::PushRequest<'a> {
     fn from(streams:Vec<logproto::Stream<'a>>)->Self{
@@ -79,27 +115,105 @@ impl<'a> From<Vec<logproto::Stream<'a>>> for logproto::PushRequest<'a> {
     }
 }
 
-impl<'a> From<&mut LogMetric> for logproto::Stream<'a> {
-    fn from(metric:&mut LogMetric)->Self {
-        let labels = get_labels_string(metric.config(),metric.labels());
-        let mut entries:Vec<logproto::Entry> = Vec::with_capacity(metric.len());
+impl<'a> From<&'a LokiStream> for logproto::Stream<'a> {
+    fn from(stream:&'a LokiStream)->Self{
+        logproto::Stream{
+            labels: Cow::Borrowed(stream.selector()),
+            entries: stream.entries.iter().map(|e|e.into()).collect()
+        }
+    }
+}
 
-        while let Some(v) = metric.pop(){
-            entries.push(v.into());
+fn drain_messages(metric:&mut LogMetric)->Vec<LogMessage>{
+    metric.collect_pending();
+    let mut messages:Vec<LogMessage> = Vec::with_capacity(metric.len());
+    while let Some(v) = metric.pop(){
+        messages.push(v);
+    }
+    messages
+}
+
+fn process_messages(metric:&mut LogMetric, mut messages:Vec<LogMessage>, redactor:Option<&Redactor>)->Vec<(Labels, Vec<LogMessage>)>{
+    let config = metric.config().clone();
+    let formatter = config.get_formatter();
+    for message in messages.iter_mut() {
+        formatter.render(message);
+    }
+    for redactor in config.get_redactor().map(|r|r.as_ref()).into_iter().chain(redactor) {
+        for message in messages.iter_mut() {
+            redactor.redact_message(message);
         }
+    }
 
-        logproto::Stream{
-            labels:  std::borrow::Cow::Owned(labels),
-            entries
+    let mut groups:Vec<(Labels, Vec<LogMessage>)> = Vec::new();
+    match config.get_pipeline() {
+        None => groups.push((Vec::new(), messages)),
+        Some(pipeline) => {
+            for mut message in messages {
+                let mut labels = match pipeline.process(&mut message) {
+                    None => {
+                        metric.record_drop(DropReason::Pipeline);
+                        continue;
+                    },
+                    Some(labels) => labels
+                };
+                labels.sort();
+                match groups.iter_mut().find(|(l, _)|*l == labels) {
+                    Some((_, group)) => group.push(message),
+                    None => groups.push((labels, vec![message]))
+                }
+            }
         }
     }
+
+    groups.into_iter()
+        .map(|(labels, mut messages)|{
+            messages.sort_by_key(|m|m.time);
+            (labels, metric.limit_lines(dedup::collapse(messages, config.get_dedup())))
+        })
+        .filter(|(_, messages)|!messages.is_empty())
+        .collect()
 }
 
-impl<'a> From<LogMessage> for logproto::Entry<'a> {
-    fn from(message:LogMessage)->Self {
+fn streams_from_messages(metric:&mut LogMetric, messages:Vec<LogMessage>, redactor:Option<&Redactor>)->Vec<LokiStream>{
+    process_messages(metric, messages, redactor)
+        .into_iter()
+        .map(|(extra, messages)|{
+            let cached = extra.is_empty();
+            // A pipeline label replaces a stream label of the same name;
+            // Loki refuses a selector that names a label twice.
+            let mut labels = metric.label_pairs();
+            for (name, value) in extra {
+                match labels.iter_mut().find(|(n, _)|*n == name) {
+                    Some(label) => label.1 = value,
+                    None => labels.push((name, value)),
+                }
+            }
+            let selector = if cached {
+                metric.selector().clone()
+            } else {
+                render_selector(labels.iter().map(|(k, v)|(k.as_str(), v.as_str()))).into()
+            };
+            LokiStream{
+                labels,
+                selector,
+                entries: messages.into_iter().map(LokiEntry::from).collect()
+            }
+        })
+        .collect()
+}
+
+impl<'a> From<&'a LokiEntry> for logproto::Entry<'a> {
+    fn from(entry:&'a LokiEntry)->Self {
         logproto::Entry {
-            ts: Some(message.time.into()),
-            line: std::borrow::Cow::Owned(message.message),
+            ts: Some(entry.ts.into()),
+            line: Cow::Borrowed(&entry.line),
+            structuredMetadata: entry.metadata.iter()
+                .map(|(name, value)|logproto::LabelPair{
+                    name: Cow::Borrowed(name),
+                    value: Cow::Borrowed(value)
+                })
+                .collect(),
         }
     }
 }
@@ -114,14 +228,28 @@ impl From<std::time::SystemTime> for logproto::Timestamp {
     }
 }
 
-fn get_labels_string(config:&LogMetricConf, values:&Vec<String>)->String{
-    let mut labels = "{".to_string();
-    let names = config.get_label_names();
-    let const_labels = config.get_const_labels();
-    let mut parts:Vec<String> = Vec::with_capacity(names.len()+const_labels.len());
-    parts.extend(const_labels.iter().map(|e|format!("{}=\"{}\"",e[0],e[1])));
-    parts.extend(names.iter().enumerate().map(|(i,e)|format!("{}=\"{}\"",e,values[i])));
-    labels.push_str(parts.join(",").as_str());
-    labels.push_str("}");
-    labels
+pub(crate) fn render_selector<'a, I>(labels:I)->String
+    where I:Iterator<Item=(&'a str, &'a str)>+Clone
+{
+    let size:usize = labels.clone().map(|(k, v)|k.len() + v.len() + 4).sum();
+    let mut selector = String::with_capacity(size + 2);
+    selector.push('{');
+    for (i, (name, value)) in labels.enumerate() {
+        if i > 0 {
+            selector.push(',');
+        }
+        selector.push_str(name);
+        selector.push_str("=\"");
+        for c in value.chars() {
+            match c {
+                '"' => selector.push_str("\\\""),
+                '\\' => selector.push_str("\\\\"),
+                '\n' => selector.push_str("\\n"),
+                c => selector.push(c),
+            }
+        }
+        selector.push('"');
+    }
+    selector.push('}');
+    selector
 }
\ No newline at end of file
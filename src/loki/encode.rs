@@ -0,0 +1,134 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{LokiStream, LokiEntry};
+
+// Field tags of logproto.PushRequest and its nested messages
+// (field number << 3 | wire type).
+const TAG_PUSH_STREAMS:u8 = 10;
+const TAG_STREAM_LABELS:u8 = 10;
+const TAG_STREAM_ENTRIES:u8 = 18;
+const TAG_ENTRY_TS:u8 = 10;
+const TAG_ENTRY_LINE:u8 = 18;
+const TAG_ENTRY_METADATA:u8 = 26;
+const TAG_PAIR_NAME:u8 = 10;
+const TAG_PAIR_VALUE:u8 = 18;
+const TAG_TS_SECONDS:u8 = 8;
+const TAG_TS_NANOS:u8 = 16;
+
+/// Writes a `logproto.PushRequest` straight from drained streams. Message
+/// sizes are computed once up front, so every byte is written exactly once
+/// into the output buffer.
+#[derive(Default)]
+pub struct ProtoEncoder {
+    sizes: Vec<usize>,
+}
+
+impl ProtoEncoder {
+    pub fn new()->Self{
+        ProtoEncoder::default()
+    }
+
+    pub fn encode(&mut self, streams:&[LokiStream], out:&mut Vec<u8>)->usize{
+        self.sizes.clear();
+        let mut total = 0;
+        for stream in streams.iter() {
+            let index = self.sizes.len();
+            self.sizes.push(0);
+            let mut size = sizeof_string(stream.selector());
+            for entry in stream.entries.iter() {
+                let entry_size = sizeof_entry(entry);
+                self.sizes.push(entry_size);
+                size += 1 + sizeof_len(entry_size);
+            }
+            self.sizes[index] = size;
+            total += 1 + sizeof_len(size);
+        }
+
+        let start = out.len();
+        out.reserve(total);
+        let mut sizes = self.sizes.iter();
+        for stream in streams.iter() {
+            out.push(TAG_PUSH_STREAMS);
+            write_varint(out, *sizes.next().unwrap() as u64);
+            write_string(out, TAG_STREAM_LABELS, stream.selector());
+            for entry in stream.entries.iter() {
+                out.push(TAG_STREAM_ENTRIES);
+                write_varint(out, *sizes.next().unwrap() as u64);
+                write_entry(out, entry);
+            }
+        }
+        out.len() - start
+    }
+}
+
+fn timestamp(ts:SystemTime)->(i64, i32){
+    let ts = ts.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (ts.as_secs() as i64, ts.subsec_nanos() as i32)
+}
+
+fn sizeof_varint(v:u64)->usize{
+    (64 - (v | 1).leading_zeros() as usize).div_ceil(7)
+}
+
+fn sizeof_len(len:usize)->usize{
+    sizeof_varint(len as u64) + len
+}
+
+fn sizeof_string(value:&str)->usize{
+    if value.is_empty() { 0 } else { 1 + sizeof_len(value.len()) }
+}
+
+fn sizeof_timestamp(seconds:i64, nanos:i32)->usize{
+    let seconds = if seconds == 0 { 0 } else { 1 + sizeof_varint(seconds as u64) };
+    let nanos = if nanos == 0 { 0 } else { 1 + sizeof_varint(nanos as i64 as u64) };
+    seconds + nanos
+}
+
+fn sizeof_pair(name:&str, value:&str)->usize{
+    sizeof_string(name) + sizeof_string(value)
+}
+
+fn sizeof_entry(entry:&LokiEntry)->usize{
+    let (seconds, nanos) = timestamp(entry.ts);
+    1 + sizeof_len(sizeof_timestamp(seconds, nanos))
+        + sizeof_string(&entry.line)
+        + entry.metadata.iter().map(|(k, v)|1 + sizeof_len(sizeof_pair(k, v))).sum::<usize>()
+}
+
+fn write_varint(out:&mut Vec<u8>, mut v:u64){
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn write_string(out:&mut Vec<u8>, tag:u8, value:&str){
+    if value.is_empty() {
+        return;
+    }
+    out.push(tag);
+    write_varint(out, value.len() as u64);
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn write_entry(out:&mut Vec<u8>, entry:&LokiEntry){
+    let (seconds, nanos) = timestamp(entry.ts);
+    out.push(TAG_ENTRY_TS);
+    write_varint(out, sizeof_timestamp(seconds, nanos) as u64);
+    if seconds != 0 {
+        out.push(TAG_TS_SECONDS);
+        write_varint(out, seconds as u64);
+    }
+    if nanos != 0 {
+        out.push(TAG_TS_NANOS);
+        write_varint(out, nanos as i64 as u64);
+    }
+    write_string(out, TAG_ENTRY_LINE, &entry.line);
+    for (name, value) in entry.metadata.iter() {
+        out.push(TAG_ENTRY_METADATA);
+        write_varint(out, sizeof_pair(name, value) as u64);
+        write_string(out, TAG_PAIR_NAME, name);
+        write_string(out, TAG_PAIR_VALUE, value);
+    }
+}
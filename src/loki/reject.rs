@@ -0,0 +1,136 @@
+use super::LokiStream;
+use crate::metrics::{self, DropReason, Registry};
+use crate::pipeline::Labels;
+
+/// A stream Loki refused to ingest, as reported in a 400 response.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rejection {
+    pub selector: String,
+    pub reason: String,
+    pub entries: usize,
+}
+
+/// Finds the streams named in a Loki 400 body, one per error line, e.g.
+///
+/// ```text
+/// entry with timestamp 2024-01-01 00:00:00 +0000 UTC ignored, reason: 'entry out of order' for stream: {app="api"},
+/// stream '{app="api"}' has label name too long: 'a_very_long_label_name'
+/// ```
+///
+/// Returns the parsed label set of each stream with the rejection reason,
+/// and whether the line names a single entry rather than the whole stream.
+pub(crate) fn parse_rejections(body:&str)->Vec<(Labels, String, bool)>{
+    body.lines()
+        .filter_map(|line|{
+            let start = line.find('{')?;
+            let (labels, len) = parse_selector(&line[start..])?;
+            let entry = line.trim_start().starts_with("entry ");
+            Some((labels, reason(line, start, start + len), entry))
+        })
+        .collect()
+}
+
+fn reason(line:&str, start:usize, end:usize)->String{
+    if let Some(at) = line.find("reason: '") {
+        let rest = &line[at + 9..];
+        if let Some(stop) = rest.find('\'') {
+            return rest[..stop].to_string();
+        }
+    }
+    let line = format!("{}{}", &line[..start], &line[end..]);
+    line.trim().trim_end_matches(',').trim().to_string()
+}
+
+/// Reads `N` from the `total ignored: N out of M` line Loki ends the body with.
+fn total_ignored(body:&str)->Option<usize>{
+    body.lines()
+        .find_map(|line|line.trim().strip_prefix("total ignored: "))
+        .and_then(|rest|rest.split_whitespace().next())
+        .and_then(|n|n.parse().ok())
+}
+
+/// Removes the streams named in a 400 body from the batch, returning what
+/// was removed. Loki still ingests the accepted entries of those streams,
+/// so they are counted as pushed right away and only the entries it ignored
+/// are counted as dropped: one per entry
+/// line, or the `total ignored` count when Loki listed fewer lines than that
+/// for a single stream. A stream-wide error drops the whole stream.
+pub(crate) fn reject_streams(streams:&mut Vec<LokiStream>, body:&str)->Vec<Rejection>{
+    let rejections = parse_rejections(body);
+    let mut rejected = Vec::new();
+    if rejections.is_empty() {
+        return rejected;
+    }
+    let listed = rejections.iter().filter(|(_, _, entry)|*entry).count();
+    let mut entry_streams:Vec<&Labels> = rejections.iter().filter(|(_, _, entry)|*entry).map(|(l, _, _)|l).collect();
+    entry_streams.sort();
+    entry_streams.dedup();
+    let unlisted = match total_ignored(body) {
+        Some(total) if total > listed && entry_streams.len() == 1 => total - listed,
+        _ => 0,
+    };
+    streams.retain(|stream|{
+        let labels = match parse_selector(stream.selector()) {
+            None => return true,
+            Some((labels, _)) => labels
+        };
+        let matching:Vec<_> = rejections.iter().filter(|(l, _, _)|*l == labels).collect();
+        let reason = match matching.first() {
+            None => return true,
+            Some((_, reason, _)) => reason.clone()
+        };
+        let entries = if matching.iter().any(|(_, _, entry)|!*entry) {
+            stream.len()
+        } else {
+            (matching.len() + unlisted).min(stream.len())
+        };
+        metrics::add_dropped(DropReason::Rejected, entries as u64);
+        Registry::global().add_pushed(stream.len() - entries);
+        rejected.push(Rejection { selector: stream.selector().to_string(), reason, entries });
+        false
+    });
+    rejected
+}
+
+/// Parses `{name="value", ...}` into label pairs sorted by name, returning
+/// them with the number of bytes consumed.
+pub(crate) fn parse_selector(s:&str)->Option<(Vec<(String, String)>, usize)>{
+    let mut chars = s.char_indices().peekable();
+    if chars.next()?.1 != '{' {
+        return None;
+    }
+    let mut labels = Vec::new();
+    loop {
+        while let Some((_, c)) = chars.peek() {
+            if c.is_whitespace() || *c == ',' { chars.next(); } else { break; }
+        }
+        let (i, c) = chars.next()?;
+        if c == '}' {
+            labels.sort();
+            return Some((labels, i + 1));
+        }
+        let mut name = c.to_string();
+        loop {
+            match chars.next()? {
+                (_, '=') => break,
+                (_, c) if c.is_alphanumeric() || c == '_' => name.push(c),
+                _ => return None,
+            }
+        }
+        if chars.next()?.1 != '"' {
+            return None;
+        }
+        let mut value = String::new();
+        loop {
+            match chars.next()?.1 {
+                '"' => break,
+                '\\' => match chars.next()?.1 {
+                    'n' => value.push('\n'),
+                    c => value.push(c),
+                },
+                c => value.push(c),
+            }
+        }
+        labels.push((name.trim().to_string(), value));
+    }
+}
@@ -1,66 +1,241 @@
 use std::sync::{Mutex, Arc};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::borrow::BorrowMut;
-use quick_protobuf::message::MessageWrite;
+use std::io::Write;
+use flate2::write::{GzEncoder, DeflateEncoder};
 
 use crate::log::LogMetric;
-use crate::scrape::{ScrapeProcess, ScrapeConfig};
-use crate::util::VecBuf;
+use crate::models::{Level, LogMessage};
+use crate::scrape::{ScrapeProcess, ScrapeConfig, ScrapeReport};
+use crate::redact::Redactor;
+use crate::metrics::Registry;
 use crate::errors::*;
-use super::{logproto};
+use super::{LokiStream, LokiModel};
+use super::encode::ProtoEncoder;
+use super::reject::{reject_streams, Rejection};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PushFormat {
+    Protobuf,
+    Json,
+}
+
+/// Body compression. Loki only reads snappy-framed protobuf, so protobuf
+/// pushes are always snappy compressed; JSON pushes use gzip or deflate
+/// through `Content-Encoding`, or go uncompressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Snappy,
+    Gzip,
+    Deflate,
+}
+
+/// Rejects a compression the format can't be sent with. `None` leaves the
+/// choice to the format: snappy for protobuf, uncompressed for JSON.
+fn check_compression(format:PushFormat, compression:Option<Compression>)->Result<()>{
+    match (format, compression) {
+        (PushFormat::Protobuf, Some(Compression::None)) | (PushFormat::Protobuf, Some(Compression::Gzip)) | (PushFormat::Protobuf, Some(Compression::Deflate)) =>
+            Err(Error::InvalidConfig("compression: protobuf pushes are always snappy compressed".to_string())),
+        (PushFormat::Json, Some(Compression::Snappy)) =>
+            Err(Error::InvalidConfig("compression: json pushes support none, gzip or deflate".to_string())),
+        _ => Ok(()),
+    }
+}
+
+impl Compression {
+    fn content_encoding(self)->Option<&'static str>{
+        match self {
+            Compression::Gzip => Some("gzip"),
+            Compression::Deflate => Some("deflate"),
+            _ => None,
+        }
+    }
+}
 
 pub struct LokiScrapeProcess{
     loki_url:String,
+    tenant:Option<String>,
     timeout_connect_ms:Option<u64>,
     timeout_write_ms:Option<u64>,
     timeout_read_ms:Option<u64>,
+    batch_entries:usize,
+    redactor:Option<Arc<Redactor>>,
+    format:PushFormat,
+    compression:Option<Compression>,
+    compression_level:Option<u32>,
+    min_compress_size:usize,
+    encoder: ProtoEncoder,
+    streams: Vec<LokiStream>,
+    pending: Option<Request>,
+    rejected: Vec<Rejection>,
     buf_in: Vec<u8>,
     buf_out: Vec<u8>
 }
 
 impl LokiScrapeProcess{
-    fn new(loki_url:String, timeout_connect_ms:Option<u64>, timeout_write_ms:Option<u64>, timeout_read_ms:Option<u64>)->Self{
+    fn new(config:&LokiScrapeConfig)->Self{
         LokiScrapeProcess {
-            loki_url,
-            timeout_connect_ms,
-            timeout_write_ms,
-            timeout_read_ms,
+            loki_url: config.loki_url.clone(),
+            tenant: config.tenant.clone(),
+            timeout_connect_ms: config.timeout_connect_ms,
+            timeout_write_ms: config.timeout_write_ms,
+            timeout_read_ms: config.timeout_read_ms,
+            batch_entries: config.batch_entries,
+            redactor: config.redactor.clone(),
+            format: config.format,
+            compression: config.compression,
+            compression_level: config.compression_level,
+            min_compress_size: config.min_compress_size,
+            encoder: ProtoEncoder::new(),
+            streams: Vec::new(),
+            pending: None,
+            rejected: Vec::new(),
             buf_in: Vec::with_capacity(65536),
             buf_out: Vec::with_capacity(65536)
         }
     }
-}
 
-impl ScrapeProcess for LokiScrapeProcess{
-    fn send(&mut self, items: std::slice::Iter<'_, Arc<Mutex<LogMetric>>>)->Result<usize>{
-        let mut streams = Vec::new();
+    fn drain_by_priority(&self, items: std::slice::Iter<'_, Arc<Mutex<LogMetric>>>, streams:&mut Vec<LokiStream>){
+        let metrics:Vec<&Arc<Mutex<LogMetric>>> = items.collect();
+        let mut batches:Vec<Vec<LogMessage>> = metrics.iter().map(|_|Vec::new()).collect();
+        let mut remaining = self.batch_entries;
+        for level in Level::ALL.iter().rev() {
+            for (i, metric) in metrics.iter().enumerate() {
+                if remaining == 0 {
+                    break;
+                }
+                let mut g = metric.lock().unwrap();
+                while remaining > 0 {
+                    match g.pop_level(*level) {
+                        Some(message) => batches[i].push(message),
+                        None => break
+                    }
+                    remaining -= 1;
+                }
+            }
+        }
+
+        streams.extend(metrics.iter().zip(batches)
+            .filter(|(_, batch)|!batch.is_empty())
+            .flat_map(|(metric, batch)|{
+                super::streams_from_messages(&mut metric.lock().unwrap(), batch, self.redactor.as_deref())
+            }));
+    }
+
+    fn drain_all(&self, items: std::slice::Iter<'_, Arc<Mutex<LogMetric>>>, streams:&mut Vec<LokiStream>){
         for metric in items{
             let mut g = metric.lock().unwrap();
             let s: &mut LogMetric = g.borrow_mut();
-            let stream = logproto::Stream::from(s);
-            if stream.entries.len()>0 {
-                streams.push(stream);
+            let messages = super::drain_messages(s);
+            if !messages.is_empty() {
+                streams.extend(super::streams_from_messages(s, messages, self.redactor.as_deref()));
             }
         }
-        if streams.len()==0 {
-            return Ok(0);
+    }
+}
+
+impl ScrapeProcess for LokiScrapeProcess{
+    fn send(&mut self, items: std::slice::Iter<'_, Arc<Mutex<LogMetric>>>)->Result<ScrapeReport>{
+        let mut streams = std::mem::take(&mut self.streams);
+        if self.batch_entries > 0 {
+            self.drain_by_priority(items, &mut streams);
+        } else {
+            self.drain_all(items, &mut streams);
         }
-        let data = logproto::PushRequest::from(streams);
+        self.rejected.clear();
+        let mut result = self.push(&mut streams);
+        if let Err(Error::Http { status: 400, body }) = &result {
+            self.rejected = reject_streams(&mut streams, body);
+            if !self.rejected.is_empty() {
+                // Loki keeps the accepted part of a partially rejected push
+                // and ignores exact duplicates, so the rest can go again.
+                result = self.push(&mut streams).map(|report|self.with_rejected(report));
+            }
+        }
+        streams.clear();
+        self.streams = streams;
+        result
+    }
 
+    fn retry(&mut self)->Result<ScrapeReport>{
+        match self.pending.take() {
+            None => Ok(ScrapeReport::default()),
+            Some(request) => self.post(request).map(|report|self.with_rejected(report)),
+        }
+    }
+
+    fn take_rejected(&mut self)->Vec<Rejection>{
+        std::mem::take(&mut self.rejected)
+    }
+}
+
+struct Request {
+    content_type: &'static str,
+    compression: Compression,
+    report: ScrapeReport,
+}
+
+impl LokiScrapeProcess{
+    // Hands the rejections of the first attempt to the report of the resend
+    // that went through; until then they wait in `rejected`.
+    fn with_rejected(&mut self, report:ScrapeReport)->ScrapeReport{
+        ScrapeReport { rejected: std::mem::take(&mut self.rejected), ..report }
+    }
+
+    fn push(&mut self, streams:&mut Vec<LokiStream>)->Result<ScrapeReport>{
+        self.pending = None;
+        if streams.is_empty() {
+            return Ok(ScrapeReport::default());
+        }
+        check_compression(self.format, self.compression)?;
+
+        let start = Instant::now();
         self.buf_in.clear();
         self.buf_out.clear();
-        let mut buf_in = VecBuf::from(&mut self.buf_in);
-        let mut writer = quick_protobuf::writer::Writer::new(&mut buf_in);
-        data.write_message(&mut writer).map_err(|e|ErrorKind::SerializeError(e))?;
+        let content_type = match self.format {
+            PushFormat::Protobuf => {
+                self.encoder.encode(streams, &mut self.buf_in);
+                "application/x-protobuf"
+            },
+            PushFormat::Json => {
+                let model = LokiModel::from(std::mem::take(streams));
+                let res = serde_json::to_writer(&mut self.buf_in, &model.to_json());
+                *streams = model.streams;
+                res?;
+                "application/json"
+            }
+        };
+        let compression = self.get_compression(self.buf_in.len());
+        let raw_bytes = self.buf_in.len();
+        let compressed_bytes = self.compress(compression)?;
 
-        self.buf_out.resize(snap::max_compress_len(buf_in.len()),0u8);
-        let size = {
-            let mut enc = snap::Encoder::new();
-            enc.compress(self.buf_in.as_slice(), self.buf_out.as_mut_slice())
-        }.chain_err(||"Snappy compress error")?;
-        self.buf_out.truncate(size);
+        self.post(Request {
+            content_type,
+            compression,
+            report: ScrapeReport {
+                streams: streams.len(),
+                entries: streams.iter().map(|s|s.len()).sum(),
+                raw_bytes,
+                compressed_bytes,
+                encode_time: start.elapsed(),
+                endpoint: Some(self.loki_url.clone()),
+                ..ScrapeReport::default()
+            },
+        })
+    }
 
+    // Sends the body in buf_out. A request that failed with a retryable
+    // error is kept so that `retry` can send it again.
+    fn post(&mut self, request:Request)->Result<ScrapeReport>{
         let mut req = ureq::request("POST", self.loki_url.as_str());
+        req.set("Content-Type", request.content_type);
+        if let Some(encoding) = request.compression.content_encoding() {
+            req.set("Content-Encoding", encoding);
+        }
+        if let Some(tenant) = &self.tenant {
+            req.set("X-Scope-OrgID", tenant);
+        }
         if let Some(timeout) = self.timeout_connect_ms{
             req.timeout_connect(timeout);
         }
@@ -70,50 +245,135 @@ impl ScrapeProcess for LokiScrapeProcess{
         if let Some(timeout) = self.timeout_read_ms{
             req.timeout_read(timeout);
         }
+        let start = Instant::now();
         let resp = req.send_bytes(self.buf_out.as_slice());
-        if resp.error(){
-            let status = resp.status();
-            let result = resp.into_string().unwrap_or_default();
-            bail!("Send request error with status: {}, and result: '{}'", status, result);
+        let latency = start.elapsed();
+        let result = match resp.synthetic_error() {
+            Some(err) => {
+                Registry::global().observe_push("error", None);
+                Err(Error::from(err))
+            },
+            None => {
+                let status = resp.status();
+                Registry::global().observe_push(&status.to_string(), Some(latency));
+                if status == 429 {
+                    let retry_after = resp.header("Retry-After").and_then(|v|v.trim().parse::<u64>().ok()).map(Duration::from_secs);
+                    Err(Error::RateLimited { retry_after })
+                } else if resp.error() {
+                    Err(Error::Http { status, body: resp.into_string().unwrap_or_default() })
+                } else {
+                    Ok(ScrapeReport { latency, status: Some(status), ..request.report.clone() })
+                }
+            }
+        };
+        if let Err(err) = &result {
+            if err.is_retryable() {
+                self.pending = Some(request);
+            }
         }
-        if let Some(err) = resp.synthetic_error(){
-            bail!("Send request error with status: '{}'({}), '{}'", err.status_text(), err.status(), err.body_text());
+        result
+    }
+
+    fn get_compression(&self, raw:usize)->Compression{
+        match (self.format, self.compression) {
+            (PushFormat::Protobuf, _) => Compression::Snappy,
+            (PushFormat::Json, Some(compression @ Compression::Gzip)) | (PushFormat::Json, Some(compression @ Compression::Deflate)) if raw >= self.min_compress_size => compression,
+            (PushFormat::Json, _) => Compression::None,
         }
+    }
 
-        Ok(size)
+    fn compress(&mut self, compression:Compression)->Result<usize>{
+        let level = self.compression_level.map_or(flate2::Compression::default(), |l|flate2::Compression::new(l.min(9)));
+        match compression {
+            Compression::None => std::mem::swap(&mut self.buf_in, &mut self.buf_out),
+            Compression::Snappy => {
+                self.buf_out.resize(snap::max_compress_len(self.buf_in.len()),0u8);
+                let size = {
+                    let mut enc = snap::Encoder::new();
+                    enc.compress(self.buf_in.as_slice(), self.buf_out.as_mut_slice())
+                }.map_err(|e|Error::Compression(Box::new(e)))?;
+                self.buf_out.truncate(size);
+            },
+            Compression::Gzip => {
+                let mut enc = GzEncoder::new(&mut self.buf_out, level);
+                enc.write_all(&self.buf_in).and_then(|_|enc.finish()).map_err(|e|Error::Compression(Box::new(e)))?;
+            },
+            Compression::Deflate => {
+                let mut enc = DeflateEncoder::new(&mut self.buf_out, level);
+                enc.write_all(&self.buf_in).and_then(|_|enc.finish()).map_err(|e|Error::Compression(Box::new(e)))?;
+            },
+        }
+        Ok(self.buf_out.len())
     }
 }
 
+#[derive(Clone)]
 pub struct LokiScrapeConfig {
     loki_url:String,
+    tenant:Option<String>,
     scrape_interval:Duration,
     timeout_connect_ms:Option<u64>,
     timeout_write_ms:Option<u64>,
     timeout_read_ms:Option<u64>,
+    flush_entries:usize,
+    flush_bytes:usize,
+    batch_entries:usize,
+    redactor:Option<Arc<Redactor>>,
+    format:PushFormat,
+    compression:Option<Compression>,
+    compression_level:Option<u32>,
+    min_compress_size:usize,
+    max_retries:u32,
+    retry_backoff:Duration,
 }
 
 #[allow(dead_code)]
 impl LokiScrapeConfig {
     pub fn new(loki_connection_string:&str, scrape_interval_ms:u64)->Self{
-        let mut parts = loki_connection_string.splitn(2, "?");
+        let mut parts = loki_connection_string.splitn(2, '?');
         let loki_url = parts.next().unwrap().into();
         let mut scrape_interval = scrape_interval_ms;
+        let mut tenant=None;
         let mut timeout_connect_ms=None;
         let mut timeout_write_ms=None;
         let mut timeout_read_ms=None;
+        let mut flush_entries=0;
+        let mut flush_bytes=0;
+        let mut batch_entries=0;
+        let mut format=PushFormat::Protobuf;
+        let mut compression=None;
+        let mut compression_level=None;
+        let mut min_compress_size=0;
+        let mut max_retries=0;
+        let mut retry_backoff_ms=500;
         if let Some(query) = parts.next(){
-            let mut parts = query.split("&");
-            while let Some(part) = parts.next() {
-                let mut pair = part.split("=");
+            for part in query.split('&') {
+                let mut pair = part.split('=');
                 let name = pair.next();
                 let value:Option<&str> = pair.next();
                 match name{
                     None=> continue,
                     Some(v)=> match v{
                         "scrape_interval" => scrape_interval=value.map_or(scrape_interval, |v|v.parse::<u64>().unwrap_or(scrape_interval)),
-                        "connect_timeout" => timeout_connect_ms=value.map_or(None, |v|v.parse::<u64>().ok()),
-                        "write_timeout" => timeout_write_ms=value.map_or(None, |v|v.parse::<u64>().ok()),
-                        "read_timeout" => timeout_read_ms=value.map_or(None, |v|v.parse::<u64>().ok()),
+                        "tenant" => tenant=value.map(|v|v.to_string()),
+                        "connect_timeout" => timeout_connect_ms=value.and_then(|v|v.parse::<u64>().ok()),
+                        "write_timeout" => timeout_write_ms=value.and_then(|v|v.parse::<u64>().ok()),
+                        "read_timeout" => timeout_read_ms=value.and_then(|v|v.parse::<u64>().ok()),
+                        "flush_entries" => flush_entries=value.map_or(flush_entries, |v|v.parse::<usize>().unwrap_or(flush_entries)),
+                        "flush_bytes" => flush_bytes=value.map_or(flush_bytes, |v|v.parse::<usize>().unwrap_or(flush_bytes)),
+                        "format" => format=match value { Some("json") => PushFormat::Json, Some("protobuf") => PushFormat::Protobuf, _ => format },
+                        "compression" => compression=match value {
+                            Some("none") => Some(Compression::None),
+                            Some("snappy") => Some(Compression::Snappy),
+                            Some("gzip") => Some(Compression::Gzip),
+                            Some("deflate") => Some(Compression::Deflate),
+                            _ => compression
+                        },
+                        "compression_level" => compression_level=value.and_then(|v|v.parse::<u32>().ok()),
+                        "min_compress_size" => min_compress_size=value.map_or(min_compress_size, |v|v.parse::<usize>().unwrap_or(min_compress_size)),
+                        "max_retries" => max_retries=value.map_or(max_retries, |v|v.parse::<u32>().unwrap_or(max_retries)),
+                        "retry_backoff" => retry_backoff_ms=value.map_or(retry_backoff_ms, |v|v.parse::<u64>().unwrap_or(retry_backoff_ms)),
+                        "batch_entries" => batch_entries=value.map_or(batch_entries, |v|v.parse::<usize>().unwrap_or(batch_entries)),
                         &_ => continue,
                     }
                 }
@@ -122,12 +382,109 @@ impl LokiScrapeConfig {
 
         LokiScrapeConfig {
             loki_url,
+            tenant,
             scrape_interval: Duration::from_millis(scrape_interval),
             timeout_connect_ms,
             timeout_write_ms,
             timeout_read_ms,
+            flush_entries,
+            flush_bytes,
+            batch_entries,
+            redactor: None,
+            format,
+            compression,
+            compression_level,
+            min_compress_size,
+            max_retries,
+            retry_backoff: Duration::from_millis(retry_backoff_ms),
         }
     }
+
+    pub fn get_loki_url(&self)->&str{
+        &self.loki_url
+    }
+
+    pub fn get_tenant(&self)->Option<&str>{
+        self.tenant.as_deref()
+    }
+
+    pub fn set_tenant(mut self, tenant:&str)->Self{
+        self.tenant = Some(tenant.to_string());
+        self
+    }
+
+    pub fn set_scrape_interval(mut self, interval:Duration)->Self{
+        self.scrape_interval = interval;
+        self
+    }
+
+    pub fn set_connect_timeout(mut self, timeout_ms:u64)->Self{
+        self.timeout_connect_ms = Some(timeout_ms);
+        self
+    }
+
+    pub fn set_write_timeout(mut self, timeout_ms:u64)->Self{
+        self.timeout_write_ms = Some(timeout_ms);
+        self
+    }
+
+    pub fn set_read_timeout(mut self, timeout_ms:u64)->Self{
+        self.timeout_read_ms = Some(timeout_ms);
+        self
+    }
+
+    pub fn set_flush_entries(mut self, entries:usize)->Self{
+        self.flush_entries = entries;
+        self
+    }
+
+    pub fn set_flush_bytes(mut self, bytes:usize)->Self{
+        self.flush_bytes = bytes;
+        self
+    }
+
+    pub fn set_batch_entries(mut self, entries:usize)->Self{
+        self.batch_entries = entries;
+        self
+    }
+
+    pub fn set_format(mut self, format:PushFormat)->Self{
+        self.format = format;
+        self
+    }
+
+    /// Sets the body compression. It must suit the format: protobuf only
+    /// goes snappy compressed and JSON never does, otherwise starting the
+    /// scrape fails with `Error::InvalidConfig`.
+    pub fn set_compression(mut self, compression:Compression)->Self{
+        self.compression = Some(compression);
+        self
+    }
+
+    pub fn set_compression_level(mut self, level:u32)->Self{
+        self.compression_level = Some(level);
+        self
+    }
+
+    pub fn set_min_compress_size(mut self, size:usize)->Self{
+        self.min_compress_size = size;
+        self
+    }
+
+    pub fn set_max_retries(mut self, max_retries:u32)->Self{
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn set_retry_backoff(mut self, backoff:Duration)->Self{
+        self.retry_backoff = backoff;
+        self
+    }
+
+    pub fn set_redactor(mut self, redactor:Arc<Redactor>)->Self{
+        self.redactor = Some(redactor);
+        self
+    }
 }
 
 impl ScrapeConfig for LokiScrapeConfig {
@@ -138,6 +495,26 @@ impl ScrapeConfig for LokiScrapeConfig {
     }
 
     fn get_scrape_process(&self)->Self::ScrapeType {
-        LokiScrapeProcess::new(self.loki_url.clone(), self.timeout_connect_ms, self.timeout_write_ms, self.timeout_read_ms)
+        LokiScrapeProcess::new(self)
+    }
+
+    fn validate(&self)->Result<()> {
+        check_compression(self.format, self.compression)
+    }
+
+    fn get_flush_entries(&self)->usize {
+        self.flush_entries
+    }
+
+    fn get_flush_bytes(&self)->usize {
+        self.flush_bytes
+    }
+
+    fn get_max_retries(&self)->u32 {
+        self.max_retries
+    }
+
+    fn get_retry_backoff(&self)->Duration {
+        self.retry_backoff
     }
 }
\ No newline at end of file
@@ -0,0 +1,59 @@
+use std::sync::Mutex;
+
+use crate::models::{Level, Location};
+use crate::log::LogMetric;
+use crate::sender::LogSender;
+
+#[doc(hidden)]
+pub trait LogTarget {
+    fn log_at(&self, level:Level, location:Location, get_msg:&mut dyn FnMut()->String)->Option<()>;
+}
+
+impl LogTarget for Mutex<LogMetric> {
+    fn log_at(&self, level:Level, location:Location, get_msg:&mut dyn FnMut()->String)->Option<()>{
+        self.lock().unwrap().push_at(level, location, get_msg)
+    }
+}
+
+impl LogTarget for LogSender {
+    fn log_at(&self, level:Level, location:Location, get_msg:&mut dyn FnMut()->String)->Option<()>{
+        self.push_at(level, location, get_msg)
+    }
+}
+
+#[macro_export]
+macro_rules! loki_log {
+    ($target:expr, $level:expr, $($arg:tt)+) => {{
+        use $crate::LogTarget as _;
+        $target.log_at(
+            $level,
+            $crate::Location { file: file!(), line: line!(), module: module_path!() },
+            &mut ||format!($($arg)+)
+        )
+    }};
+}
+
+#[macro_export]
+macro_rules! loki_trace {
+    ($target:expr, $($arg:tt)+) => { $crate::loki_log!($target, $crate::Level::Trace, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! loki_debug {
+    ($target:expr, $($arg:tt)+) => { $crate::loki_log!($target, $crate::Level::Debug, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! loki_info {
+    ($target:expr, $($arg:tt)+) => { $crate::loki_log!($target, $crate::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! loki_warn {
+    ($target:expr, $($arg:tt)+) => { $crate::loki_log!($target, $crate::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! loki_error {
+    ($target:expr, $($arg:tt)+) => { $crate::loki_log!($target, $crate::Level::Error, $($arg)+) };
+}
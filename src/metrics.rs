@@ -0,0 +1,176 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::log::Log;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DropReason {
+    Capacity,
+    InboxFull,
+    RateLimited,
+    Sampled,
+    Oversized,
+    Pipeline,
+    Rejected,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 7] = [DropReason::Capacity, DropReason::InboxFull, DropReason::RateLimited, DropReason::Sampled, DropReason::Oversized, DropReason::Pipeline, DropReason::Rejected];
+
+    pub fn as_str(self)->&'static str{
+        match self {
+            DropReason::Capacity => "capacity",
+            DropReason::InboxFull => "inbox_full",
+            DropReason::RateLimited => "rate_limited",
+            DropReason::Sampled => "sampled",
+            DropReason::Oversized => "oversized",
+            DropReason::Pipeline => "pipeline",
+            DropReason::Rejected => "rejected",
+        }
+    }
+}
+
+const LATENCY_BUCKETS:[f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, value:Duration){
+        let secs = value.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|b|secs <= *b) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos.fetch_add(value.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+pub struct Registry {
+    entries_pushed: AtomicU64,
+    entries_dropped: [AtomicU64; DropReason::ALL.len()],
+    push_requests: Mutex<BTreeMap<String, u64>>,
+    push_latency: Histogram,
+}
+
+lazy_static!{
+    static ref REGISTRY: Registry = Registry {
+        entries_pushed: AtomicU64::new(0),
+        entries_dropped: Default::default(),
+        push_requests: Mutex::new(BTreeMap::new()),
+        push_latency: Histogram { buckets: Default::default(), count: AtomicU64::new(0), sum_nanos: AtomicU64::new(0) },
+    };
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StreamStats {
+    pub container: String,
+    pub labels: Vec<(String, String)>,
+    pub buffered: usize,
+    pub dropped: u64,
+}
+
+pub(crate) fn add_dropped(reason:DropReason, count:u64){
+    REGISTRY.entries_dropped[reason as usize].fetch_add(count, Ordering::Relaxed);
+}
+
+#[allow(dead_code)]
+impl Registry {
+    pub fn global()->&'static Registry{
+        &REGISTRY
+    }
+
+    pub fn entries_pushed(&self)->u64{
+        self.entries_pushed.load(Ordering::Relaxed)
+    }
+
+    pub fn entries_dropped(&self, reason:DropReason)->u64{
+        self.entries_dropped[reason as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn push_requests(&self, status:&str)->u64{
+        self.push_requests.lock().unwrap().get(status).cloned().unwrap_or(0)
+    }
+
+    pub fn push_latency_count(&self)->u64{
+        self.push_latency.count.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn add_pushed(&self, entries:usize){
+        self.entries_pushed.fetch_add(entries as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn observe_push(&self, status:&str, latency:Option<Duration>){
+        *self.push_requests.lock().unwrap().entry(status.to_string()).or_insert(0) += 1;
+        if let Some(latency) = latency {
+            self.push_latency.observe(latency);
+        }
+    }
+
+    pub fn streams(&self)->Vec<StreamStats>{
+        Log::containers().iter()
+            .flat_map(|container|{
+                let name = container.name();
+                let names = container.config().get_label_names().clone();
+                container.map(|metric|StreamStats {
+                    container: name.clone(),
+                    labels: names.iter().cloned().zip(metric.label_values().iter().map(|v|v.to_string())).collect(),
+                    buffered: metric.len() + metric.pending(),
+                    dropped: metric.dropped_total(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn render(&self)->String{
+        let mut out = String::with_capacity(2048);
+        header(&mut out, "log_loki_entries_pushed_total", "counter", "Entries accepted by Loki.");
+        let _ = writeln!(out, "log_loki_entries_pushed_total {}", self.entries_pushed());
+
+        header(&mut out, "log_loki_entries_dropped_total", "counter", "Entries dropped before reaching Loki.");
+        for reason in DropReason::ALL.iter() {
+            let _ = writeln!(out, "log_loki_entries_dropped_total{{reason=\"{}\"}} {}", reason.as_str(), self.entries_dropped(*reason));
+        }
+
+        header(&mut out, "log_loki_push_requests_total", "counter", "Push requests sent to Loki by response status.");
+        for (status, count) in self.push_requests.lock().unwrap().iter() {
+            let _ = writeln!(out, "log_loki_push_requests_total{{status=\"{}\"}} {}", status, count);
+        }
+
+        header(&mut out, "log_loki_push_duration_seconds", "histogram", "Latency of push requests to Loki.");
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(self.push_latency.buckets.iter()) {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = writeln!(out, "log_loki_push_duration_seconds_bucket{{le=\"{}\"}} {}", bound, cumulative);
+        }
+        let count = self.push_latency.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "log_loki_push_duration_seconds_bucket{{le=\"+Inf\"}} {}", count);
+        let _ = writeln!(out, "log_loki_push_duration_seconds_sum {}", self.push_latency.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9);
+        let _ = writeln!(out, "log_loki_push_duration_seconds_count {}", count);
+
+        header(&mut out, "log_loki_buffered_entries", "gauge", "Entries waiting to be pushed, per container.");
+        let mut buffered:BTreeMap<String, usize> = BTreeMap::new();
+        for stream in self.streams() {
+            *buffered.entry(stream.container).or_insert(0) += stream.buffered;
+        }
+        for (container, count) in buffered.iter() {
+            let _ = writeln!(out, "log_loki_buffered_entries{{container=\"{}\"}} {}", escape(container), count);
+        }
+        out
+    }
+}
+
+fn header(out:&mut String, name:&str, kind:&str, help:&str){
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape(value:&str)->String{
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -1,14 +1,148 @@
-use std::time::SystemTime;
+use std::time::{SystemTime, Duration};
+use std::sync::Arc;
 use std::hash::Hasher;
 use fnv::FnvHasher;
+use regex::Regex;
+
+use crate::redact::Redactor;
+use crate::pipeline::Pipeline;
+use crate::format::{Field, Formatter};
+use crate::context::{ContextProvider, ContextMode};
 
 const DEFAULT_CAPACITY:usize=1024;
+const DEFAULT_MULTILINE_MAX_LINES:usize=128;
+const DEFAULT_MULTILINE_MAX_WAIT:Duration=Duration::from_secs(3);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Trace,
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    pub const ALL: [Level; 5] = [Level::Trace, Level::Debug, Level::Info, Level::Warn, Level::Error];
+
+    pub fn index(self)->usize{
+        self as usize
+    }
+
+    pub fn as_str(self)->&'static str{
+        match self {
+            Level::Trace => "trace",
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sampling {
+    All,
+    EveryNth(u64),
+    Ratio(f64),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Dedup {
+    #[default]
+    Off,
+    Consecutive,
+    Window,
+}
+
+#[derive(Clone, Debug)]
+enum MultilineMatch {
+    FirstLine(Regex),
+    Continuation(Regex),
+}
+
+#[derive(Clone, Debug)]
+pub struct Multiline {
+    matcher: MultilineMatch,
+    max_lines: usize,
+    max_wait: Duration,
+}
+
+impl Multiline {
+    pub fn with_first_line(regex: Regex) -> Self {
+        Multiline::with_matcher(MultilineMatch::FirstLine(regex))
+    }
+
+    pub fn with_continuation(regex: Regex) -> Self {
+        Multiline::with_matcher(MultilineMatch::Continuation(regex))
+    }
+
+    fn with_matcher(matcher: MultilineMatch) -> Self {
+        Multiline {
+            matcher,
+            max_lines: DEFAULT_MULTILINE_MAX_LINES,
+            max_wait: DEFAULT_MULTILINE_MAX_WAIT,
+        }
+    }
+
+    pub fn set_max_lines(mut self, max_lines: usize) -> Self {
+        self.max_lines = max_lines;
+        self
+    }
+
+    pub fn set_max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = max_wait;
+        self
+    }
+
+    pub fn get_max_lines(&self) -> usize {
+        self.max_lines
+    }
+
+    pub fn get_max_wait(&self) -> Duration {
+        self.max_wait
+    }
+
+    pub fn is_continuation(&self, line: &str) -> bool {
+        match &self.matcher {
+            MultilineMatch::FirstLine(regex) => !regex.is_match(line),
+            MultilineMatch::Continuation(regex) => regex.is_match(line),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinePolicy {
+    Truncate,
+    Split,
+    Drop,
+}
 
 #[derive(Clone)]
 pub struct LogMetricConfBuilder{
     const_labels: Vec<[String;2]>,
     label_names:Vec<String>,
     default_capacity: usize,
+    reserved_capacity: [usize; Level::ALL.len()],
+    flush_level: Option<Level>,
+    min_level: Level,
+    capture_location: bool,
+    rate_limit: Option<RateLimit>,
+    sampling: Sampling,
+    dedup: Dedup,
+    multiline: Option<Multiline>,
+    redactor: Option<Arc<Redactor>>,
+    pipeline: Option<Pipeline>,
+    max_line_size: Option<(usize, LinePolicy)>,
+    formatter: Formatter,
+    context: Option<(Arc<dyn ContextProvider>, ContextMode)>,
 }
 
 impl Default for LogMetricConfBuilder{
@@ -16,7 +150,20 @@ impl Default for LogMetricConfBuilder{
         LogMetricConfBuilder {
             label_names: Vec::new(),
             default_capacity: DEFAULT_CAPACITY,
-            const_labels: Vec::new()
+            const_labels: Vec::new(),
+            reserved_capacity: [0; Level::ALL.len()],
+            flush_level: None,
+            min_level: Level::Trace,
+            capture_location: false,
+            rate_limit: None,
+            sampling: Sampling::All,
+            dedup: Dedup::Off,
+            multiline: None,
+            redactor: None,
+            pipeline: None,
+            max_line_size: None,
+            formatter: Formatter::Plain,
+            context: None,
         }
     }
 }
@@ -32,6 +179,76 @@ impl LogMetricConfBuilder {
         self
     }
 
+    pub fn reserve_capacity(mut self, level: Level, capacity: usize) -> Self {
+        self.reserved_capacity[level.index()] = capacity;
+        self
+    }
+
+    pub fn set_flush_level(mut self, level: Level) -> Self {
+        self.flush_level = Some(level);
+        self
+    }
+
+    pub fn set_min_level(mut self, level: Level) -> Self {
+        self.min_level = level;
+        self
+    }
+
+    pub fn set_capture_location(mut self, capture: bool) -> Self {
+        self.capture_location = capture;
+        self
+    }
+
+    pub fn set_rate_limit(mut self, per_second: f64, burst: usize) -> Self {
+        self.rate_limit = Some(RateLimit { per_second, burst });
+        self
+    }
+
+    pub fn set_sample_every(mut self, n: u64) -> Self {
+        self.sampling = match n { 0 | 1 => Sampling::All, n => Sampling::EveryNth(n) };
+        self
+    }
+
+    pub fn set_sample_ratio(mut self, ratio: f64) -> Self {
+        self.sampling = if ratio >= 1.0 { Sampling::All } else { Sampling::Ratio(ratio.max(0.0)) };
+        self
+    }
+
+    pub fn set_dedup(mut self, dedup: Dedup) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    pub fn set_multiline(mut self, multiline: Multiline) -> Self {
+        self.multiline = Some(multiline);
+        self
+    }
+
+    pub fn set_redactor(mut self, redactor: Arc<Redactor>) -> Self {
+        self.redactor = Some(redactor);
+        self
+    }
+
+    pub fn set_pipeline(mut self, pipeline: Pipeline) -> Self {
+        self.pipeline = if pipeline.is_empty() { None } else { Some(pipeline) };
+        self
+    }
+
+    pub fn set_max_line_size(mut self, size: usize, policy: LinePolicy) -> Self {
+        self.max_line_size = if size == 0 { None } else { Some((size, policy)) };
+        self
+    }
+
+    pub fn set_formatter(mut self, formatter: Formatter) -> Self {
+        self.formatter = formatter;
+        self
+    }
+
+    pub fn set_context_provider(mut self, provider: Arc<dyn ContextProvider>, mode: ContextMode) -> Self {
+        self.context = Some((provider, mode));
+        self
+    }
+
     pub fn add_label<T:Into<String>>(mut self, label_name:T)->Self{
         self.label_names.push(label_name.into());
         self
@@ -54,6 +271,19 @@ impl LogMetricConfBuilder {
             default_capacity: self.default_capacity,
             const_labels:self.const_labels,
             label_names:self.label_names,
+            reserved_capacity: self.reserved_capacity,
+            flush_level: self.flush_level,
+            min_level: self.min_level,
+            capture_location: self.capture_location,
+            rate_limit: self.rate_limit,
+            sampling: self.sampling,
+            dedup: self.dedup,
+            multiline: self.multiline,
+            redactor: self.redactor,
+            pipeline: self.pipeline,
+            max_line_size: self.max_line_size,
+            formatter: self.formatter,
+            context: self.context,
         }
     }
 
@@ -74,6 +304,19 @@ pub struct LogMetricConf {
     const_labels: Vec<[String;2]>,
     label_names:Vec<String>,
     default_capacity: usize,
+    reserved_capacity: [usize; Level::ALL.len()],
+    flush_level: Option<Level>,
+    min_level: Level,
+    capture_location: bool,
+    rate_limit: Option<RateLimit>,
+    sampling: Sampling,
+    dedup: Dedup,
+    multiline: Option<Multiline>,
+    redactor: Option<Arc<Redactor>>,
+    pipeline: Option<Pipeline>,
+    max_line_size: Option<(usize, LinePolicy)>,
+    formatter: Formatter,
+    context: Option<(Arc<dyn ContextProvider>, ContextMode)>,
     key:u64
 }
 impl LogMetricConf {
@@ -93,20 +336,105 @@ impl LogMetricConf {
         self.default_capacity = capacity;
     }
 
+    pub fn get_reserved_capacity(&self, level:Level)->usize{
+        self.reserved_capacity[level.index()]
+    }
+
+    pub fn get_reserved_total(&self)->usize{
+        self.reserved_capacity.iter().sum()
+    }
+
+    pub fn get_flush_level(&self)->Option<Level>{
+        self.flush_level
+    }
+
+    pub fn get_min_level(&self)->Level{
+        self.min_level
+    }
+
+    pub fn get_capture_location(&self)->bool{
+        self.capture_location
+    }
+
+    pub fn get_rate_limit(&self)->Option<RateLimit>{
+        self.rate_limit
+    }
+
+    pub fn get_sampling(&self)->Sampling{
+        self.sampling
+    }
+
+    pub fn get_dedup(&self)->Dedup{
+        self.dedup
+    }
+
+    pub fn get_multiline(&self)->Option<&Multiline>{
+        self.multiline.as_ref()
+    }
+
+    pub fn get_redactor(&self)->Option<&Arc<Redactor>>{
+        self.redactor.as_ref()
+    }
+
+    pub fn get_pipeline(&self)->Option<&Pipeline>{
+        self.pipeline.as_ref()
+    }
+
+    pub fn get_max_line_size(&self)->Option<(usize, LinePolicy)>{
+        self.max_line_size
+    }
+
+    pub fn get_formatter(&self)->&Formatter{
+        &self.formatter
+    }
+
+    pub fn get_context_provider(&self)->Option<(&Arc<dyn ContextProvider>, ContextMode)>{
+        self.context.as_ref().map(|(provider, mode)|(provider, *mode))
+    }
+
     pub fn get_key(&self)->u64{
         self.key
     }
 }
 
+pub type Metadata = Vec<(String, String)>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Location {
+    pub file: &'static str,
+    pub line: u32,
+    pub module: &'static str,
+}
+
 pub struct LogMessage {
     pub time:SystemTime,
+    pub level:Level,
     pub message:String,
+    pub fields:Vec<Field>,
+    pub metadata:Metadata,
 }
-impl<T: Into<String>> From<T> for LogMessage{
-    fn from(msg:T)->Self{
+impl LogMessage {
+    pub fn with_level<T: Into<String>>(level:Level, msg:T)->Self{
+        LogMessage::with_fields(level, msg, Vec::new())
+    }
+
+    pub fn with_fields<T: Into<String>>(level:Level, msg:T, fields:Vec<Field>)->Self{
         LogMessage{
             time: SystemTime::now(),
-            message: msg.into()
+            level,
+            message: msg.into(),
+            fields,
+            metadata: Vec::new(),
         }
     }
-}
\ No newline at end of file
+
+    pub fn add_metadata<K: Into<String>, V: Into<String>>(mut self, name:K, value:V)->Self{
+        self.metadata.push((name.into(), value.into()));
+        self
+    }
+}
+impl<T: Into<String>> From<T> for LogMessage{
+    fn from(msg:T)->Self{
+        LogMessage::with_level(Level::default(), msg)
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use chrono::{DateTime, NaiveDateTime};
+use regex::Regex;
+use serde_json::Value;
+
+use crate::models::LogMessage;
+use crate::format::expand_template;
+
+pub type Labels = Vec<(String, String)>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimestampFormat {
+    Rfc3339,
+    Unix,
+    UnixMs,
+    UnixNs,
+}
+
+#[derive(Clone, Debug)]
+enum Stage {
+    Regex { source: Option<String>, regex: Regex },
+    Json { source: Option<String>, fields: Vec<(String, String)> },
+    Logfmt { source: Option<String>, fields: Vec<String> },
+    Template { template: String },
+    Drop { source: Option<String>, regex: Regex },
+    Labels { labels: Vec<(String, String)> },
+    Timestamp { source: String, format: TimestampFormat },
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Pipeline {
+    stages: Vec<Stage>,
+}
+
+struct Entry<'a> {
+    message: &'a mut LogMessage,
+    extracted: HashMap<String, String>,
+    labels: Labels,
+}
+
+impl<'a> Entry<'a> {
+    fn source(&self, source:&Option<String>)->Option<&str>{
+        match source {
+            None => Some(self.message.message.as_str()),
+            Some(name) => self.extracted.get(name).map(|v|v.as_str()),
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl Pipeline {
+    pub fn new()->Self{
+        Pipeline::default()
+    }
+
+    pub fn regex(self, regex:Regex)->Self{
+        self.stage(Stage::Regex { source: None, regex })
+    }
+
+    pub fn regex_from<T:Into<String>>(self, source:T, regex:Regex)->Self{
+        self.stage(Stage::Regex { source: Some(source.into()), regex })
+    }
+
+    pub fn json(self, fields:&[(&str, &str)])->Self{
+        self.stage(Stage::Json { source: None, fields: to_pairs(fields) })
+    }
+
+    pub fn json_from<T:Into<String>>(self, source:T, fields:&[(&str, &str)])->Self{
+        self.stage(Stage::Json { source: Some(source.into()), fields: to_pairs(fields) })
+    }
+
+    pub fn logfmt(self, fields:&[&str])->Self{
+        self.stage(Stage::Logfmt { source: None, fields: fields.iter().map(|e|(*e).into()).collect() })
+    }
+
+    pub fn logfmt_from<T:Into<String>>(self, source:T, fields:&[&str])->Self{
+        self.stage(Stage::Logfmt { source: Some(source.into()), fields: fields.iter().map(|e|(*e).into()).collect() })
+    }
+
+    pub fn template<T:Into<String>>(self, template:T)->Self{
+        self.stage(Stage::Template { template: template.into() })
+    }
+
+    pub fn drop(self, regex:Regex)->Self{
+        self.stage(Stage::Drop { source: None, regex })
+    }
+
+    pub fn drop_from<T:Into<String>>(self, source:T, regex:Regex)->Self{
+        self.stage(Stage::Drop { source: Some(source.into()), regex })
+    }
+
+    pub fn labels(self, labels:&[(&str, &str)])->Self{
+        self.stage(Stage::Labels { labels: to_pairs(labels) })
+    }
+
+    pub fn timestamp<T:Into<String>>(self, source:T, format:TimestampFormat)->Self{
+        self.stage(Stage::Timestamp { source: source.into(), format })
+    }
+
+    pub fn is_empty(&self)->bool{
+        self.stages.is_empty()
+    }
+
+    pub fn process(&self, message:&mut LogMessage)->Option<Labels>{
+        let mut entry = Entry { message, extracted: HashMap::new(), labels: Vec::new() };
+        for stage in self.stages.iter() {
+            match stage {
+                Stage::Regex { source, regex } => {
+                    let found:Vec<(String, String)> = match entry.source(source).and_then(|s|regex.captures(s)) {
+                        None => continue,
+                        Some(caps) => regex.capture_names()
+                            .flatten()
+                            .filter_map(|name|caps.name(name).map(|v|(name.to_string(), v.as_str().to_string())))
+                            .collect()
+                    };
+                    entry.extracted.extend(found);
+                },
+                Stage::Json { source, fields } => {
+                    let json:Value = match entry.source(source).and_then(|s|serde_json::from_str(s).ok()) {
+                        None => continue,
+                        Some(v) => v
+                    };
+                    for (name, path) in fields.iter() {
+                        if let Some(value) = json_path(&json, path) {
+                            entry.extracted.insert(name.clone(), value);
+                        }
+                    }
+                },
+                Stage::Logfmt { source, fields } => {
+                    let found:Vec<(String, String)> = match entry.source(source) {
+                        None => continue,
+                        Some(s) => parse_logfmt(s).into_iter()
+                            .filter(|(k, _)|fields.is_empty() || fields.contains(k))
+                            .collect()
+                    };
+                    entry.extracted.extend(found);
+                },
+                Stage::Template { template } => {
+                    let line = render_template(template, &entry.extracted, &entry.message.message);
+                    entry.message.message = line;
+                },
+                Stage::Drop { source, regex } => {
+                    if entry.source(source).is_some_and(|s|regex.is_match(s)) {
+                        return None;
+                    }
+                },
+                Stage::Labels { labels } => {
+                    for (label, field) in labels.iter() {
+                        if let Some(value) = entry.extracted.get(field) {
+                            entry.labels.retain(|(k, _)|k != label);
+                            entry.labels.push((label.clone(), value.clone()));
+                        }
+                    }
+                },
+                Stage::Timestamp { source, format } => {
+                    if let Some(time) = entry.extracted.get(source).and_then(|v|parse_timestamp(v, *format)) {
+                        entry.message.time = time;
+                    }
+                },
+            }
+        }
+        Some(entry.labels)
+    }
+
+    fn stage(mut self, stage:Stage)->Self{
+        self.stages.push(stage);
+        self
+    }
+}
+
+fn to_pairs(pairs:&[(&str, &str)])->Vec<(String, String)>{
+    pairs.iter().map(|(k, v)|((*k).into(), (*v).into())).collect()
+}
+
+fn json_path(json:&Value, path:&str)->Option<String>{
+    let mut value = json;
+    for part in path.split('.') {
+        value = match value {
+            Value::Array(items) => items.get(part.parse::<usize>().ok()?)?,
+            _ => value.get(part)?,
+        };
+    }
+    match value {
+        Value::Null => None,
+        Value::String(s) => Some(s.clone()),
+        v => Some(v.to_string()),
+    }
+}
+
+fn parse_logfmt(line:&str)->Vec<(String, String)>{
+    let mut result = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c|c.is_whitespace()) {
+            chars.next();
+        }
+        let mut key = String::new();
+        while let Some(&c) = chars.peek() {
+            if c == '=' || c.is_whitespace() {
+                break;
+            }
+            key.push(c);
+            chars.next();
+        }
+        if key.is_empty() {
+            if chars.next().is_none() {
+                break;
+            }
+            continue;
+        }
+        let mut value = String::new();
+        if chars.peek() == Some(&'=') {
+            chars.next();
+            if chars.peek() == Some(&'"') {
+                chars.next();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => if let Some(n) = chars.next() { value.push(n) },
+                        c => value.push(c),
+                    }
+                }
+            } else {
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    value.push(c);
+                    chars.next();
+                }
+            }
+        }
+        result.push((key, value));
+    }
+    result
+}
+
+fn render_template(template:&str, extracted:&HashMap<String, String>, line:&str)->String{
+    expand_template(template, line.len(), |name, result|match extracted.get(name) {
+        Some(value) => result.push_str(value),
+        None if name == "line" => result.push_str(line),
+        None => {}
+    })
+}
+
+fn parse_timestamp(value:&str, format:TimestampFormat)->Option<SystemTime>{
+    let value = value.trim();
+    match format {
+        TimestampFormat::Rfc3339 => DateTime::parse_from_rfc3339(value).ok()
+            .or_else(||NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M:%S%.f").ok().map(|t|t.and_utc().fixed_offset()))
+            .map(SystemTime::from),
+        TimestampFormat::Unix => value.parse::<f64>().ok().and_then(|v|Duration::try_from_secs_f64(v).ok()).and_then(|d|UNIX_EPOCH.checked_add(d)),
+        TimestampFormat::UnixMs => value.parse::<u64>().ok().and_then(|v|UNIX_EPOCH.checked_add(Duration::from_millis(v))),
+        TimestampFormat::UnixNs => value.parse::<u64>().ok().and_then(|v|UNIX_EPOCH.checked_add(Duration::from_nanos(v))),
+    }
+}
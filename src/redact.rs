@@ -0,0 +1,163 @@
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use regex::{Regex, Captures};
+
+use crate::models::LogMessage;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuiltinRule {
+    Email,
+    CreditCard,
+    BearerToken,
+    AwsAccessKey,
+    Jwt,
+}
+
+impl BuiltinRule {
+    pub const ALL: [BuiltinRule; 5] = [BuiltinRule::Email, BuiltinRule::CreditCard, BuiltinRule::BearerToken, BuiltinRule::AwsAccessKey, BuiltinRule::Jwt];
+
+    fn name(self)->&'static str{
+        match self {
+            BuiltinRule::Email => "email",
+            BuiltinRule::CreditCard => "credit_card",
+            BuiltinRule::BearerToken => "bearer_token",
+            BuiltinRule::AwsAccessKey => "aws_access_key",
+            BuiltinRule::Jwt => "jwt",
+        }
+    }
+
+    fn pattern(self)->&'static str{
+        match self {
+            BuiltinRule::Email => r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}",
+            BuiltinRule::CreditCard => r"\b(?:\d[ -]?){12,18}\d\b",
+            BuiltinRule::BearerToken => r"(?i)\bbearer\s+[A-Za-z0-9\-._~+/]+=*",
+            BuiltinRule::AwsAccessKey => r"\b(?:AKIA|ASIA)[0-9A-Z]{16}\b",
+            BuiltinRule::Jwt => r"\beyJ[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+\.[A-Za-z0-9_-]+",
+        }
+    }
+}
+
+enum Replacement {
+    Text(String),
+    Hash,
+}
+
+struct RedactRule {
+    name: String,
+    regex: Regex,
+    replacement: Replacement,
+    count: AtomicU64,
+}
+
+pub struct Redactor {
+    rules: Vec<RedactRule>,
+    key: Vec<u8>,
+}
+
+// Without a configured key, hashes are keyed with random bytes and are
+// only stable within the process.
+impl Default for Redactor {
+    fn default()->Self{
+        let state = RandomState::new();
+        let key = (0..4u64).flat_map(|i|{
+            let mut h = state.build_hasher();
+            h.write_u64(i);
+            h.finish().to_le_bytes()
+        }).collect();
+        Redactor { rules: Vec::new(), key }
+    }
+}
+
+impl Redactor {
+    pub fn new()->Self{
+        Redactor::default()
+    }
+
+    pub fn with_builtin_rules()->Self{
+        BuiltinRule::ALL.iter().fold(Redactor::new(), |r, rule|r.add_builtin(*rule))
+    }
+
+    pub fn add_rule<T:Into<String>>(self, name:T, regex:Regex, replacement:T)->Self{
+        self.push_rule(name.into(), regex, Replacement::Text(replacement.into()))
+    }
+
+    pub fn add_hash_rule<T:Into<String>>(self, name:T, regex:Regex)->Self{
+        self.push_rule(name.into(), regex, Replacement::Hash)
+    }
+
+    pub fn add_builtin(self, rule:BuiltinRule)->Self{
+        let regex = Regex::new(rule.pattern()).unwrap();
+        self.push_rule(rule.name().into(), regex, Replacement::Text(format!("<{}>", rule.name())))
+    }
+
+    pub fn add_builtin_hashed(self, rule:BuiltinRule)->Self{
+        let regex = Regex::new(rule.pattern()).unwrap();
+        self.push_rule(rule.name().into(), regex, Replacement::Hash)
+    }
+
+    /// Secret key for hash rules. Hashes are HMAC-SHA256, so without the
+    /// key a pseudonym can't be matched to a guessed value; keep the key
+    /// the same across processes for pseudonyms that stay stable.
+    pub fn set_hash_key<K:AsRef<[u8]>>(mut self, key:K)->Self{
+        self.key = key.as_ref().to_vec();
+        self
+    }
+
+    pub fn count(&self, name:&str)->u64{
+        self.rules.iter()
+            .filter(|r|r.name == name)
+            .map(|r|r.count.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    pub fn counts(&self)->Vec<(String, u64)>{
+        self.rules.iter().map(|r|(r.name.clone(), r.count.load(Ordering::Relaxed))).collect()
+    }
+
+    pub fn redact<'a>(&self, line:&'a str)->Cow<'a, str>{
+        let mut result = Cow::Borrowed(line);
+        for rule in self.rules.iter() {
+            let mut found = 0;
+            let replaced = rule.regex.replace_all(&result, |caps:&Captures|{
+                found += 1;
+                match &rule.replacement {
+                    Replacement::Text(text) => text.clone(),
+                    Replacement::Hash => format!("{}:{:016x}", rule.name, self.hash(&caps[0])),
+                }
+            });
+            if found > 0 {
+                rule.count.fetch_add(found, Ordering::Relaxed);
+                result = Cow::Owned(replaced.into_owned());
+            }
+        }
+        result
+    }
+
+    /// Redacts the line and the structured metadata values of a message.
+    pub(crate) fn redact_message(&self, message:&mut LogMessage){
+        if let Cow::Owned(line) = self.redact(&message.message) {
+            message.message = line;
+        }
+        for (_, value) in message.metadata.iter_mut() {
+            if let Cow::Owned(redacted) = self.redact(value) {
+                *value = redacted;
+            }
+        }
+    }
+
+    fn push_rule(mut self, name:String, regex:Regex, replacement:Replacement)->Self{
+        self.rules.push(RedactRule { name, regex, replacement, count: AtomicU64::new(0) });
+        self
+    }
+
+    fn hash(&self, value:&str)->u64{
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(value.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        digest[..8].iter().fold(0, |h, b|h << 8 | u64::from(*b))
+    }
+}
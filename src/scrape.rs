@@ -1,35 +1,147 @@
 use crate::models::{LogMetricConf};
-use crate::log::{LogContainer,LogMetric,Log};
+use crate::log::{LogContainer,LogMetric,Log,Suppressed};
+use crate::flush::FlushTrigger;
+use crate::labels::{LokiLabels, TypedContainer};
+use crate::metrics::{Registry, DropReason};
+use crate::status::{Health, StatusServer};
 use crate::errors::*;
+use crate::loki::Rejection;
 
+use std::any::{Any, TypeId};
 use std::collections::{HashMap};
 use std::sync::{Mutex, Arc, atomic::AtomicBool};
 use std::time::{Duration, Instant};
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::thread::JoinHandle;
 
 use std::sync::atomic::Ordering;
 use std::cell::Cell;
 
-type ContainersType = Arc<Mutex<HashMap<u64, Arc<Mutex<LogContainer>>>>>;
+const MAX_RETRY_BACKOFF:Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy)]
+struct Retries {
+    max: u32,
+    backoff: Duration,
+}
+
+type ContainersType = Arc<Mutex<HashMap<u64, Arc<LogContainer>>>>;
+// A replacement config waiting for the worker, or why loading one failed.
+type ReloadSlot = Arc<Mutex<Option<Result<Box<dyn Any + Send>>>>>;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScrapeReport {
+    pub streams: usize,
+    pub entries: usize,
+    pub raw_bytes: usize,
+    pub compressed_bytes: usize,
+    pub encode_time: Duration,
+    pub latency: Duration,
+    pub status: Option<u16>,
+    pub endpoint: Option<String>,
+    pub rejected: Vec<Rejection>,
+}
+
+impl From<usize> for ScrapeReport {
+    fn from(size:usize)->Self{
+        ScrapeReport { raw_bytes: size, compressed_bytes: size, ..ScrapeReport::default() }
+    }
+}
+
+/// Entries still buffered across all streams, waiting for a later push.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Backlog {
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+impl Backlog {
+    fn of(trigger:&FlushTrigger)->Self{
+        Backlog { entries: trigger.entries(), bytes: trigger.bytes() }
+    }
+}
 
 pub trait ScrapeProcess {
-    fn send(&mut self, items: std::slice::Iter<'_, Arc<Mutex<LogMetric>>>)->Result<usize>;
+    fn send(&mut self, items: std::slice::Iter<'_, Arc<Mutex<LogMetric>>>)->Result<ScrapeReport>;
+    /// Sends the last request again after a retryable error. Processes that
+    /// can't replay a request have nothing to resend.
+    fn retry(&mut self)->Result<ScrapeReport>{
+        Ok(ScrapeReport::default())
+    }
+    /// Streams rejected by a push whose remaining streams then failed, so
+    /// they can still be reported alongside the error.
+    fn take_rejected(&mut self)->Vec<Rejection>{
+        Vec::new()
+    }
 }
 
 pub trait ScrapeConfig {
     type ScrapeType:ScrapeProcess;
     fn get_scrape_interval(&self)->Duration;
     fn get_scrape_process(&self)->Self::ScrapeType;
+    fn get_flush_entries(&self)->usize{ 0 }
+    fn get_flush_bytes(&self)->usize{ 0 }
+    fn get_max_retries(&self)->u32{ 0 }
+    fn get_retry_backoff(&self)->Duration{ Duration::from_millis(500) }
+    /// Checks settings that only make sense together. An invalid config is
+    /// refused by `Scrape::start` and `Scrape::reload`.
+    fn validate(&self)->Result<()>{ Ok(()) }
 }
 
+/// Callbacks invoked from the scrape thread. Every method has an empty
+/// default, and the trait is object safe, so listeners can be boxed or
+/// shared behind an `Arc`.
 #[allow(unused_variables)]
 pub trait ScrapeEvents {
     fn on_start(&self){}
     fn on_after_scrape(&self, size:usize){}
-    fn on_error<T:std::error::Error>(&self, err:T){}
+    fn on_scrape_report(&self, report:&ScrapeReport){
+        self.on_after_scrape(report.compressed_bytes)
+    }
+    /// Lines of one stream suppressed by sampling or rate limits since the
+    /// previous scrape. `labels` are the stream's name/value pairs.
+    fn on_suppressed(&self, labels:&[(String, String)], suppressed:Suppressed){}
+    /// Entries of one stream dropped since the previous scrape.
+    fn on_dropped(&self, labels:&[(String, String)], reason:DropReason, count:u64){}
+    /// A stream Loki refused; its entries were dropped and the rest resent.
+    fn on_rejected(&self, rejection:&Rejection){}
+    /// A failed push is about to be retried after `delay`.
+    fn on_retry(&self, attempt:u32, delay:Duration, err:&dyn std::error::Error){}
+    /// Loki asked us to slow down; the next push waits at least `delay`.
+    fn on_throttled(&self, delay:Duration){}
+    fn on_error(&self, err:&dyn std::error::Error){}
+    /// Result of the last push made while stopping.
+    fn on_shutdown_flush(&self, result:std::result::Result<&ScrapeReport, &dyn std::error::Error>){}
+    /// A new config was applied, or loading one failed and the old one stays.
+    fn on_reload(&self, result:std::result::Result<(), &dyn std::error::Error>){}
+    /// What is left buffered after each scrape and after the shutdown flush.
+    fn on_backlog(&self, backlog:&Backlog){}
     fn on_end(&self){}
 }
 
+macro_rules! forward_scrape_events {
+    ($ptr:ident) => {
+        impl<T:ScrapeEvents + ?Sized> ScrapeEvents for $ptr<T> {
+            fn on_start(&self){ (**self).on_start() }
+            fn on_after_scrape(&self, size:usize){ (**self).on_after_scrape(size) }
+            fn on_scrape_report(&self, report:&ScrapeReport){ (**self).on_scrape_report(report) }
+            fn on_suppressed(&self, labels:&[(String, String)], suppressed:Suppressed){ (**self).on_suppressed(labels, suppressed) }
+            fn on_dropped(&self, labels:&[(String, String)], reason:DropReason, count:u64){ (**self).on_dropped(labels, reason, count) }
+            fn on_rejected(&self, rejection:&Rejection){ (**self).on_rejected(rejection) }
+            fn on_retry(&self, attempt:u32, delay:Duration, err:&dyn std::error::Error){ (**self).on_retry(attempt, delay, err) }
+            fn on_throttled(&self, delay:Duration){ (**self).on_throttled(delay) }
+            fn on_error(&self, err:&dyn std::error::Error){ (**self).on_error(err) }
+            fn on_shutdown_flush(&self, result:std::result::Result<&ScrapeReport, &dyn std::error::Error>){ (**self).on_shutdown_flush(result) }
+            fn on_reload(&self, result:std::result::Result<(), &dyn std::error::Error>){ (**self).on_reload(result) }
+            fn on_backlog(&self, backlog:&Backlog){ (**self).on_backlog(backlog) }
+            fn on_end(&self){ (**self).on_end() }
+        }
+    };
+}
+
+forward_scrape_events!(Box);
+forward_scrape_events!(Arc);
+
 pub struct ScrapeEmptyListener;
 impl ScrapeEvents for ScrapeEmptyListener{}
 
@@ -37,6 +149,18 @@ pub struct Scrape {
     containers:ContainersType,
     worker:Cell<Option<JoinHandle<()>>>,
     cancellation:Arc<AtomicBool>,
+    trigger:Arc<FlushTrigger>,
+    health:Arc<Health>,
+    status:Cell<Option<StatusServer>>,
+    reload:ReloadSlot,
+    config_type:Cell<Option<TypeId>>,
+    watcher:Cell<Option<(Arc<FlushTrigger>, JoinHandle<()>)>>,
+}
+
+impl Default for Scrape {
+    fn default()->Self{
+        Scrape::new()
+    }
 }
 
 #[allow(dead_code)]
@@ -47,7 +171,13 @@ impl Scrape {
         Scrape{
             containers,
             worker: Cell::new(None),
-            cancellation
+            cancellation,
+            trigger: Arc::new(FlushTrigger::new()),
+            health: Arc::new(Health::default()),
+            status: Cell::new(None),
+            reload: Arc::new(Mutex::new(None)),
+            config_type: Cell::new(None),
+            watcher: Cell::new(None),
         }
     }
 
@@ -57,6 +187,9 @@ impl Scrape {
         self.start_with_listener(config, ScrapeEmptyListener{})
     }
 
+    /// Starts the scrape worker. Returns `None` when it is already running,
+    /// or when the config fails `ScrapeConfig::validate`; the error goes to
+    /// the listener's `on_error`.
     pub fn start_with_listener<T,Te>(&self, config:T, events_listener:Te)->Option<()>
         where T:'static+ScrapeConfig+Send, Te:'static+ScrapeEvents+Send
     {
@@ -65,24 +198,104 @@ impl Scrape {
             self.worker.replace(worker);
             return None;
         }
+        if let Err(err) = config.validate() {
+            events_listener.on_error(&err);
+            return None;
+        }
 
+        self.trigger.set_limits(config.get_flush_entries(), config.get_flush_bytes());
         let containers = self.containers.clone();
         let cancellation = self.cancellation.clone();
-        let worker = std::thread::spawn(move||scrape(config, events_listener, containers, cancellation));
+        let trigger = self.trigger.clone();
+        let health = self.health.clone();
+        let reload = self.reload.clone();
+        self.config_type.set(Some(TypeId::of::<T>()));
+        let worker = std::thread::spawn(move||scrape(config, events_listener, containers, cancellation, trigger, health, reload));
         self.worker.replace(Some(worker));
         Some(())
     }
 
+    /// Swaps the config of the running scrape. The worker rebuilds its
+    /// process before the next push; buffered entries are kept. Returns
+    /// `None` when not running or when `T` differs from the started config.
+    /// A config that fails `ScrapeConfig::validate` reaches the listener as
+    /// a failed reload and the running config stays.
+    pub fn reload<T>(&self, config:T)->Option<()>
+        where T:'static+ScrapeConfig+Send
+    {
+        if self.config_type.get()? != TypeId::of::<T>() {
+            return None;
+        }
+        let config:Result<Box<dyn Any + Send>> = config.validate().map(|_|Box::new(config) as Box<dyn Any + Send>);
+        *self.reload.lock().unwrap() = Some(config);
+        self.trigger.notify();
+        Some(())
+    }
+
+    /// Polls `path` every `poll` and reloads the push settings from its
+    /// `[loki]` table whenever the file changes. Stream defaults from the
+    /// `[log]` table only apply to containers created afterwards through
+    /// `Config::log`, so they are not reloaded here.
+    #[cfg(feature = "toml")]
+    pub fn watch_config<P:Into<std::path::PathBuf>>(&self, path:P, poll:Duration)->Option<()>{
+        if self.config_type.get()? != TypeId::of::<crate::loki::LokiScrapeConfig>() {
+            return None;
+        }
+        self.stop_watch_config();
+        let path = path.into();
+        let wake = Arc::new(FlushTrigger::new());
+        let worker = {
+            let (wake, reload, trigger) = (wake.clone(), self.reload.clone(), self.trigger.clone());
+            std::thread::spawn(move||watch(path, poll, wake, reload, trigger))
+        };
+        self.watcher.set(Some((wake, worker)));
+        Some(())
+    }
+
+    pub fn stop_watch_config(&self){
+        if let Some((wake, worker)) = self.watcher.replace(None) {
+            wake.notify();
+            let _ = worker.join();
+        }
+    }
+
     pub fn stop(&self)->Option<()> {
+        self.stop_watch_config();
         let worker:JoinHandle<()> = self.worker.replace(None)?;
+        self.config_type.set(None);
         self.cancellation.store(true, Ordering::Relaxed);
+        self.trigger.notify();
         worker.join().ok()
     }
 
-    pub fn get (&self, config:LogMetricConf)->Arc<Mutex<LogContainer>>{
+    pub fn start_status_server<A:ToSocketAddrs>(&self, addr:A)->std::io::Result<SocketAddr>{
+        let server = StatusServer::start(addr, self.health.clone())?;
+        let addr = server.local_addr();
+        drop(self.status.replace(Some(server)));
+        Ok(addr)
+    }
+
+    pub fn stop_status_server(&self){
+        drop(self.status.replace(None));
+    }
+
+    pub fn health(&self)->&Arc<Health>{
+        &self.health
+    }
+
+    pub fn get_typed<L:LokiLabels>(&self)->TypedContainer<L>{
+        TypedContainer::new(self.get(L::conf()))
+    }
+
+    pub fn get (&self, config:LogMetricConf)->Arc<LogContainer>{
+        let trigger = self.trigger.clone();
         self.containers.lock().unwrap()
             .entry(config.get_key())
-            .or_insert_with(||Log::create(config).unwrap())
+            .or_insert_with(||{
+                let container = Log::get(config);
+                container.set_flush_trigger(trigger);
+                container
+            })
             .clone()
     }
 }
@@ -90,41 +303,166 @@ impl Scrape {
 impl Drop for Scrape{
     fn drop(&mut self){
         self.stop();
+        self.stop_status_server();
     }
 }
 
-#[allow(dead_code)]
-fn scrape<T,Te>(config:T, event_listener:Te, containers: ContainersType, cancellation:Arc<AtomicBool>)
+fn collect_and_send<S,Te>(process:&mut S, event_listener:&Te, containers:&ContainersType, metrics:&mut Vec<Arc<Mutex<LogMetric>>>, last:bool)->Result<ScrapeReport>
+    where S:ScrapeProcess, Te:ScrapeEvents
+{
+    let mut suppressed = Vec::new();
+    for container in containers.lock().unwrap().values(){
+        for metric in container.values(){
+            let mut m = metric.lock().unwrap();
+            m.collect_pending();
+            if last {
+                m.close_multiline();
+            }
+            if let Some(s) = m.flush_suppressed() {
+                suppressed.push((m.label_pairs(), s));
+            }
+            metrics.push(metric.clone());
+        }
+    }
+    for (labels, s) in suppressed.drain(..) {
+        event_listener.on_suppressed(&labels, s);
+    }
+
+    let result = process.send(metrics.iter());
+
+    // Reported after the send so drops found while draining, e.g. by a
+    // pipeline stage, reach the listener along with those counted at push.
+    let mut dropped = Vec::new();
+    for metric in metrics.drain(..) {
+        let mut m = metric.lock().unwrap();
+        let drops = m.take_drops();
+        if drops.iter().any(|d|*d > 0) {
+            dropped.push((m.label_pairs(), drops));
+        }
+    }
+    for (labels, drops) in dropped.drain(..) {
+        for (reason, count) in DropReason::ALL.iter().zip(drops.iter()) {
+            if *count > 0 {
+                event_listener.on_dropped(&labels, *reason, *count);
+            }
+        }
+    }
+    result
+}
+
+// Retries retryable failures with exponential backoff, or the delay Loki
+// asked for when throttled. Gives up early when the scrape is stopped.
+fn send_with_retries<S,Te>(process:&mut S, event_listener:&Te, mut result:Result<ScrapeReport>, retries:Retries, cancellation:&AtomicBool, trigger:&FlushTrigger)->Result<ScrapeReport>
+    where S:ScrapeProcess, Te:ScrapeEvents
+{
+    let mut attempt = 0;
+    let mut backoff = retries.backoff;
+    loop {
+        let err = match result {
+            Err(err) if err.is_retryable() && attempt < retries.max && !cancellation.load(Ordering::Relaxed) => err,
+            result => return result,
+        };
+        attempt += 1;
+        let delay = match err.retry_after() {
+            Some(delay) => delay,
+            None => backoff,
+        };
+        backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+        if let Error::RateLimited { .. } = err {
+            event_listener.on_throttled(delay);
+        }
+        event_listener.on_retry(attempt, delay, &err);
+        // A flush requested meanwhile stays pending for the main loop.
+        trigger.sleep(delay, cancellation);
+        if cancellation.load(Ordering::Relaxed) {
+            return Err(err);
+        }
+        result = process.retry();
+    }
+}
+
+// Waits for the file's modification time to change, then queues the new
+// push settings, or the error, for the worker. A notify on `wake` stops it.
+#[cfg(feature = "toml")]
+fn watch(path:std::path::PathBuf, poll:Duration, wake:Arc<FlushTrigger>, reload:ReloadSlot, trigger:Arc<FlushTrigger>){
+    let modified = |path:&std::path::Path|std::fs::metadata(path).and_then(|m|m.modified()).ok();
+    let mut last = modified(&path);
+    while !wake.wait(poll) {
+        let current = modified(&path);
+        if current == last {
+            continue;
+        }
+        last = current;
+        let config = crate::config::Config::from_file(&path)
+            .map(|c|Box::new(c.into_parts().0) as Box<dyn Any + Send>);
+        *reload.lock().unwrap() = Some(config);
+        trigger.notify();
+    }
+}
+
+fn scrape<T,Te>(mut config:T, event_listener:Te, containers: ContainersType, cancellation:Arc<AtomicBool>, trigger:Arc<FlushTrigger>, health:Arc<Health>, reload:ReloadSlot)
     where T:'static+ScrapeConfig+Send, Te:'static+ScrapeEvents+Send
 {
     event_listener.on_start();
     let mut s = config.get_scrape_process();
-    let interval = config.get_scrape_interval();
+    let mut interval = config.get_scrape_interval();
+    let mut retries = Retries { max: config.get_max_retries(), backoff: config.get_retry_backoff() };
     let mut metrics = Vec::new();
-    std::thread::sleep(interval.clone());
-    let mut start = std::time::Instant::now();
+    trigger.wait(interval);
     while !cancellation.load(Ordering::Relaxed) {
-        for container in containers.lock().unwrap().values(){
-            for metric in container.lock().unwrap().values(){
-                metrics.push(metric.clone());
-            }
+        let pending = reload.lock().unwrap().take();
+        match pending.map(|r|r.map(|c|c.downcast::<T>())) {
+            None => {},
+            Some(Ok(Ok(next))) => {
+                config = *next;
+                s = config.get_scrape_process();
+                interval = config.get_scrape_interval();
+                retries = Retries { max: config.get_max_retries(), backoff: config.get_retry_backoff() };
+                trigger.set_limits(config.get_flush_entries(), config.get_flush_bytes());
+                event_listener.on_reload(Ok(()));
+            },
+            Some(Ok(Err(_))) => event_listener.on_reload(Err(&Error::InvalidConfig("config type does not match the running scrape".to_string()))),
+            Some(Err(err)) => event_listener.on_reload(Err(&err)),
         }
 
-        match s.send(metrics.iter()){
-            Err(err)=>event_listener.on_error(err),
-            Ok(size)=>event_listener.on_after_scrape(size)
+        let start = Instant::now();
+        let result = collect_and_send(&mut s, &event_listener, &containers, &mut metrics, false);
+        match send_with_retries(&mut s, &event_listener, result, retries, &cancellation, &trigger){
+            Err(err)=>{
+                health.record(false);
+                s.take_rejected().iter().for_each(|r|event_listener.on_rejected(r));
+                event_listener.on_error(&err)
+            },
+            Ok(report)=>{
+                health.record(true);
+                Registry::global().add_pushed(report.entries);
+                report.rejected.iter().for_each(|r|event_listener.on_rejected(r));
+                event_listener.on_scrape_report(&report)
+            }
         }
+        event_listener.on_backlog(&Backlog::of(&trigger));
 
-        metrics.clear();
-        let end = Instant::now();
-        let duration = end.duration_since(start);
-        start = end;
+        let duration = start.elapsed();
+        if duration<interval && !cancellation.load(Ordering::Relaxed) {
+            trigger.wait(interval-duration);
+        }
+    }
 
-        if duration<interval {
-            let duration = interval-duration;
-            std::thread::sleep(duration);
-            start += duration;
+    // Push whatever was buffered between the last scrape and the stop,
+    // including multiline messages still waiting for continuation lines.
+    match collect_and_send(&mut s, &event_listener, &containers, &mut metrics, true){
+        Err(err)=>{
+            health.record(false);
+            s.take_rejected().iter().for_each(|r|event_listener.on_rejected(r));
+            event_listener.on_shutdown_flush(Err(&err))
+        },
+        Ok(report)=>{
+            health.record(true);
+            Registry::global().add_pushed(report.entries);
+            report.rejected.iter().for_each(|r|event_listener.on_rejected(r));
+            event_listener.on_shutdown_flush(Ok(&report))
         }
     }
+    event_listener.on_backlog(&Backlog::of(&trigger));
     event_listener.on_end();
-}
\ No newline at end of file
+}
@@ -0,0 +1,207 @@
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use arc_swap::ArcSwap;
+
+use crate::models::{LogMessage, LogMetricConf, Level, Location};
+use crate::flush::FlushTrigger;
+use crate::util::Ring;
+use crate::metrics::{self, DropReason};
+use crate::log::{Admission, Suppressed};
+use crate::context::attach_current;
+
+pub(crate) struct Inbox {
+    // The ring rounds its size up to a power of two; `capacity` is the
+    // configured bound and `total` counts the slots taken against it.
+    ring: Ring<LogMessage>,
+    capacity: usize,
+    total: AtomicUsize,
+    config: ArcSwap<LogMetricConf>,
+    queued: [AtomicUsize; Level::ALL.len()],
+    dropped: [AtomicU64; Level::ALL.len()],
+    sampled: AtomicU64,
+    rate_limited: AtomicU64,
+    admission: Arc<Admission>,
+    trigger: OnceLock<Arc<FlushTrigger>>,
+}
+
+impl Inbox {
+    pub(crate) fn new(capacity:usize, config:Arc<LogMetricConf>, admission:Arc<Admission>)->Self{
+        Inbox {
+            ring: Ring::with_capacity(capacity),
+            capacity,
+            total: AtomicUsize::new(0),
+            config: ArcSwap::new(config),
+            queued: Default::default(),
+            dropped: Default::default(),
+            sampled: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+            admission,
+            trigger: OnceLock::new(),
+        }
+    }
+
+    pub(crate) fn len(&self)->usize{
+        self.ring.len()
+    }
+
+    // Senders load the config on every push, so a stream's set_config
+    // reaches the senders already handed out.
+    pub(crate) fn set_config(&self, config:Arc<LogMetricConf>){
+        self.config.store(config);
+    }
+
+    pub(crate) fn set_flush_trigger(&self, trigger:Arc<FlushTrigger>){
+        let _ = self.trigger.set(trigger);
+    }
+
+    pub(crate) fn pop(&self)->Option<LogMessage>{
+        let message = self.ring.pop()?;
+        self.total.fetch_sub(1, Ordering::Relaxed);
+        self.queued[message.level.index()].fetch_sub(1, Ordering::Relaxed);
+        if let Some(trigger) = self.trigger.get() {
+            trigger.sub(1, message.message.len());
+        }
+        Some(message)
+    }
+
+    pub(crate) fn take_dropped(&self, level:Level)->u64{
+        self.dropped[level.index()].swap(0, Ordering::Relaxed)
+    }
+
+    pub(crate) fn take_suppressed(&self)->Suppressed{
+        Suppressed {
+            rate_limited: self.rate_limited.swap(0, Ordering::Relaxed),
+            sampled: self.sampled.swap(0, Ordering::Relaxed),
+        }
+    }
+
+    // Same split as the stream's lanes: a level may always fill its own
+    // reserved slots, and shares whatever is left of the ring with the
+    // other levels.
+    fn has_room(&self, config:&LogMetricConf, level:Level)->bool{
+        let index = level.index();
+        if self.queued[index].load(Ordering::Relaxed) < config.get_reserved_capacity(level) {
+            return true;
+        }
+        let shared = self.capacity.saturating_sub(config.get_reserved_total());
+        let shared_used:usize = Level::ALL.iter()
+            .map(|l|self.queued[l.index()].load(Ordering::Relaxed).saturating_sub(config.get_reserved_capacity(*l)))
+            .sum();
+        shared_used < shared
+    }
+}
+
+#[derive(Clone)]
+pub struct LogSender {
+    inbox: Arc<Inbox>,
+}
+
+impl LogSender {
+    pub(crate) fn new(inbox:Arc<Inbox>)->Self{
+        LogSender { inbox }
+    }
+
+    pub fn len(&self)->usize{
+        self.inbox.len()
+    }
+
+    pub fn is_empty(&self)->bool{
+        self.inbox.len() == 0
+    }
+
+    pub fn can_push_level(&self, level:Level)->Option<()>{
+        let config = self.inbox.config.load();
+        if level < config.get_min_level() || !self.inbox.has_room(&config, level) {
+            return None;
+        }
+        Some(())
+    }
+
+    pub fn push<T:Into<String>>(&self, level:Level, message:T)->Option<()>{
+        self.push_with(level, ||LogMessage::with_level(level, message))
+    }
+
+    pub fn push_lazy<F,Ft>(&self, level:Level, get_msg:F)->Option<()>
+        where Ft:Into<String>, F:FnOnce()->Ft
+    {
+        self.push_with(level, ||LogMessage::with_level(level, get_msg()))
+    }
+
+    pub fn push_at<F,Ft>(&self, level:Level, location:Location, get_msg:F)->Option<()>
+        where Ft:Into<String>, F:FnOnce()->Ft
+    {
+        let capture = self.inbox.config.load().get_capture_location();
+        self.push_with(level, ||{
+            let fields = if capture {
+                vec![("file", location.file.into()), ("line", location.line.into()), ("module", location.module.into())]
+            } else {
+                Vec::new()
+            };
+            LogMessage::with_fields(level, get_msg(), fields)
+        })
+    }
+
+    pub fn push_entry(&self, message:LogMessage)->Option<()>{
+        let level = message.level;
+        self.push_with(level, ||message)
+    }
+
+    fn push_with<F>(&self, level:Level, make:F)->Option<()>
+        where F:FnOnce()->LogMessage
+    {
+        let config = self.inbox.config.load_full();
+        if level < config.get_min_level() {
+            return None;
+        }
+        // Room is checked before sampling and rate limits, so a message
+        // dropped for capacity doesn't use up their slot.
+        if self.can_push_level(level).is_none() {
+            self.inbox.dropped[level.index()].fetch_add(1, Ordering::Relaxed);
+            metrics::add_dropped(DropReason::InboxFull, 1);
+            return None;
+        }
+        // has_room is only a hint when several threads push at once; taking
+        // a slot here keeps the inbox within its configured capacity.
+        if self.inbox.total.fetch_add(1, Ordering::Relaxed) >= self.inbox.capacity {
+            self.inbox.total.fetch_sub(1, Ordering::Relaxed);
+            self.inbox.dropped[level.index()].fetch_add(1, Ordering::Relaxed);
+            metrics::add_dropped(DropReason::InboxFull, 1);
+            return None;
+        }
+        if let Err(reason) = self.inbox.admission.check() {
+            self.inbox.total.fetch_sub(1, Ordering::Relaxed);
+            match reason {
+                DropReason::Sampled => &self.inbox.sampled,
+                _ => &self.inbox.rate_limited,
+            }.fetch_add(1, Ordering::Relaxed);
+            metrics::add_dropped(reason, 1);
+            return None;
+        }
+        let mut message = make();
+        attach_current(&config, &mut message);
+        let bytes = message.message.len();
+        let queued = &self.inbox.queued[message.level.index()];
+        queued.fetch_add(1, Ordering::Relaxed);
+        let trigger = self.inbox.trigger.get();
+        if let Some(trigger) = trigger {
+            trigger.add(1, bytes);
+        }
+        if let Err(message) = self.inbox.ring.push(message) {
+            self.inbox.admission.refund();
+            self.inbox.total.fetch_sub(1, Ordering::Relaxed);
+            queued.fetch_sub(1, Ordering::Relaxed);
+            if let Some(trigger) = trigger {
+                trigger.sub(1, bytes);
+            }
+            self.inbox.dropped[message.level.index()].fetch_add(1, Ordering::Relaxed);
+            metrics::add_dropped(DropReason::InboxFull, 1);
+            return None;
+        }
+        if let Some(trigger) = trigger {
+            if config.get_flush_level().is_some_and(|flush|level >= flush) {
+                trigger.notify();
+            }
+        }
+        Some(())
+    }
+}
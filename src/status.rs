@@ -0,0 +1,143 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde_json::{json, Map, Value};
+
+use crate::metrics::Registry;
+
+const TIMEOUT:Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+pub struct Health {
+    consecutive_failures: AtomicU64,
+    last_success_ms: AtomicU64,
+}
+
+#[allow(dead_code)]
+impl Health {
+    pub fn record(&self, success:bool){
+        if success {
+            self.consecutive_failures.store(0, Ordering::Relaxed);
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            self.last_success_ms.store(now.as_millis() as u64, Ordering::Relaxed);
+        } else {
+            self.consecutive_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn consecutive_failures(&self)->u64{
+        self.consecutive_failures.load(Ordering::Relaxed)
+    }
+
+    pub fn is_healthy(&self)->bool{
+        self.consecutive_failures() == 0
+    }
+
+    fn to_json(&self)->Value{
+        let last_success = match self.last_success_ms.load(Ordering::Relaxed) {
+            0 => Value::Null,
+            ms => Value::from(ms),
+        };
+        json!({
+            "status": if self.is_healthy() { "ok" } else { "failing" },
+            "consecutive_failures": self.consecutive_failures(),
+            "last_success_ms": last_success,
+        })
+    }
+}
+
+pub struct StatusServer {
+    addr: SocketAddr,
+    cancellation: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl StatusServer {
+    pub fn start<A:ToSocketAddrs>(addr:A, health:Arc<Health>)->std::io::Result<Self>{
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let cancellation = Arc::new(AtomicBool::new(false));
+        let worker = {
+            let cancellation = cancellation.clone();
+            std::thread::spawn(move||serve(listener, health, cancellation))
+        };
+        Ok(StatusServer { addr, cancellation, worker: Some(worker) })
+    }
+
+    pub fn local_addr(&self)->SocketAddr{
+        self.addr
+    }
+
+    pub fn stop(&mut self){
+        let worker = match self.worker.take() {
+            None => return,
+            Some(worker) => worker
+        };
+        self.cancellation.store(true, Ordering::Relaxed);
+        // Wake the blocking accept so the worker sees the cancellation.
+        let _ = TcpStream::connect_timeout(&self.addr, TIMEOUT);
+        let _ = worker.join();
+    }
+}
+
+impl Drop for StatusServer {
+    fn drop(&mut self){
+        self.stop();
+    }
+}
+
+fn serve(listener:TcpListener, health:Arc<Health>, cancellation:Arc<AtomicBool>){
+    for stream in listener.incoming() {
+        if cancellation.load(Ordering::Relaxed) {
+            break;
+        }
+        // Each connection gets its own thread, so a slow or idle client
+        // can't hold up /healthz and /metrics for everyone else.
+        if let Ok(stream) = stream {
+            let health = health.clone();
+            std::thread::spawn(move||handle(stream, &health));
+        }
+    }
+}
+
+fn handle(mut stream:TcpStream, health:&Health)->std::io::Result<()>{
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut parts = request.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let path = path.split('?').next().unwrap_or("");
+    let (status, content_type, body) = match (method, path) {
+        ("GET", "/metrics") => ("200 OK", "text/plain; version=0.0.4", Registry::global().render()),
+        ("GET", "/healthz") => {
+            let status = if health.is_healthy() { "200 OK" } else { "503 Service Unavailable" };
+            (status, "application/json", health.to_json().to_string())
+        },
+        ("GET", "/debug/streams") => ("200 OK", "application/json", debug_streams().to_string()),
+        ("GET", _) => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "text/plain", "method not allowed\n".to_string()),
+    };
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, content_type, body.len())?;
+    stream.write_all(body.as_bytes())?;
+    stream.flush()
+}
+
+fn debug_streams()->Value{
+    let streams:Vec<Value> = Registry::global().streams().into_iter()
+        .map(|s|{
+            let labels:Map<String, Value> = s.labels.into_iter().map(|(k, v)|(k, Value::String(v))).collect();
+            json!({ "container": s.container, "labels": labels, "buffered": s.buffered, "dropped": s.dropped })
+        })
+        .collect();
+    json!({ "streams": streams })
+}
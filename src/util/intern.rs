@@ -0,0 +1,64 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+// A sweep runs once the set has doubled since the last one, but never below
+// this size.
+const MIN_SWEEP:usize = 1024;
+
+struct Values {
+    set: HashSet<Arc<str>>,
+    sweep_at: usize,
+}
+
+// Values are shared while some stream holds them. Once the interner holds the
+// only reference the value is dropped at the next sweep, so label values of
+// removed streams don't pile up for the life of the process.
+pub struct Interner {
+    values: Mutex<Values>,
+}
+
+impl Default for Interner {
+    fn default()->Self{
+        Interner { values: Mutex::new(Values { set: HashSet::new(), sweep_at: MIN_SWEEP }) }
+    }
+}
+
+#[allow(dead_code)]
+impl Interner {
+    pub fn new()->Self{
+        Interner::default()
+    }
+
+    pub fn intern(&self, value:&str)->Arc<str>{
+        let mut values = self.values.lock().unwrap();
+        if let Some(v) = values.set.get(value) {
+            return v.clone();
+        }
+        if values.set.len() >= values.sweep_at {
+            Interner::sweep_values(&mut values);
+        }
+        let v:Arc<str> = Arc::from(value);
+        values.set.insert(v.clone());
+        v
+    }
+
+    /// Drops every value no longer used outside the interner.
+    pub fn sweep(&self){
+        Interner::sweep_values(&mut self.values.lock().unwrap());
+    }
+
+    pub fn len(&self)->usize{
+        self.values.lock().unwrap().set.len()
+    }
+
+    pub fn is_empty(&self)->bool{
+        self.len() == 0
+    }
+
+    // Clones are only handed out under the lock, so a count of one can't
+    // go up while we look at it.
+    fn sweep_values(values:&mut Values){
+        values.set.retain(|v|Arc::strong_count(v) > 1);
+        values.sweep_at = (values.set.len() * 2).max(MIN_SWEEP);
+    }
+}
@@ -1,3 +1,7 @@
-mod vecbuf;
+mod intern;
+mod ring;
+mod xorshift;
 
-pub use vecbuf::VecBuf;
\ No newline at end of file
+pub use intern::Interner;
+pub use ring::Ring;
+pub use xorshift::XorShift;
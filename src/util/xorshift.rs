@@ -0,0 +1,27 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct XorShift(u64);
+
+impl XorShift {
+    pub fn with_seed(seed:u64)->Self{
+        XorShift(if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed })
+    }
+
+    pub fn from_time(salt:u64)->Self{
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d|d.as_nanos() as u64).unwrap_or_default();
+        XorShift::with_seed(nanos ^ salt)
+    }
+
+    pub fn next_u64(&mut self)->u64{
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    pub fn next_f64(&mut self)->f64{
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}